@@ -0,0 +1,327 @@
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Outcome {
+    Success,
+    // an error or a timeout caused by the overloaded downstream
+    Failure,
+    // not caused by the downstream (e.g. a validation error), the limit is not changed
+    Ignored
+}
+
+/*
+   An algorithm adjusting the concurrency limit after each completed request.
+   in_flight is the number of requests in flight when the request completed, including the request itself.
+ */
+pub trait LimitAlgorithm: Send {
+    fn update(&mut self, limit: usize, in_flight: usize, rtt: Duration, outcome: Outcome) -> usize;
+}
+
+/*
+   Additive increase, multiplicative decrease.
+   The limit grows by one after a successful request and is cut by backoff_ratio after a failure,
+   a request slower than the timeout is a failure.
+   The limit grows only when at least half of it is used, so an idle period does not inflate it.
+ */
+pub struct Aimd {
+    min_limit: usize,
+    max_limit: usize,
+    backoff_ratio: f64,
+    timeout: Duration
+}
+
+impl Aimd {
+    pub fn new(min_limit: usize, max_limit: usize, backoff_ratio: f64, timeout: Duration) -> Self {
+        assert!(min_limit > 0 && min_limit <= max_limit);
+        assert!(backoff_ratio > 0.0 && backoff_ratio < 1.0);
+
+        Aimd { min_limit, max_limit, backoff_ratio, timeout }
+    }
+}
+
+impl LimitAlgorithm for Aimd {
+    fn update(&mut self, limit: usize, in_flight: usize, rtt: Duration, outcome: Outcome) -> usize {
+        let new_limit = match outcome {
+            Outcome::Failure => (limit as f64 * self.backoff_ratio) as usize,
+            Outcome::Success if rtt > self.timeout => (limit as f64 * self.backoff_ratio) as usize,
+            Outcome::Success if in_flight * 2 >= limit => limit + 1,
+            _ => limit
+        };
+        new_limit.clamp(self.min_limit, self.max_limit)
+    }
+}
+
+/*
+   A gradient (Vegas-style) algorithm comparing the latency of each request with the long-term average latency.
+   While the latency stays at the average the limit grows by a queue of sqrt(limit),
+   when the latency rises (requests start to queue downstream) the limit shrinks by the ratio of the latencies.
+   A failure halves the limit, changes are smoothed to avoid oscillation.
+ */
+pub struct Gradient {
+    min_limit: usize,
+    max_limit: usize,
+    // latencies up to tolerance * the average are not considered queueing
+    tolerance: f64,
+    smoothing: f64,
+    // the number of requests the average latency is taken over
+    window: f64,
+    // nanoseconds
+    long_rtt: f64,
+    estimated_limit: f64
+}
+
+impl Gradient {
+    pub fn new(min_limit: usize, max_limit: usize) -> Self {
+        assert!(min_limit > 0 && min_limit <= max_limit);
+
+        Gradient {
+            min_limit,
+            max_limit,
+            tolerance: 1.5,
+            smoothing: 0.2,
+            window: 100.0,
+            long_rtt: 0.0,
+            estimated_limit: 0.0
+        }
+    }
+}
+
+impl LimitAlgorithm for Gradient {
+    fn update(&mut self, limit: usize, in_flight: usize, rtt: Duration, outcome: Outcome) -> usize {
+        if outcome == Outcome::Ignored {
+            return limit;
+        }
+        if self.estimated_limit == 0.0 {
+            self.estimated_limit = limit as f64;
+        }
+
+        let rtt = rtt.as_nanos().max(1) as f64;
+        self.long_rtt = if self.long_rtt == 0.0 { rtt } else { self.long_rtt + (rtt - self.long_rtt) / self.window };
+
+        let new_limit = if outcome == Outcome::Failure {
+            self.estimated_limit / 2.0
+        } else if in_flight * 2 < limit {
+            // the limit is not used, there is nothing to learn about it
+            return limit;
+        } else {
+            let gradient = (self.tolerance * self.long_rtt / rtt).clamp(0.5, 1.0);
+            self.estimated_limit * gradient + self.estimated_limit.sqrt()
+        };
+        self.estimated_limit = (self.estimated_limit * (1.0 - self.smoothing) + new_limit * self.smoothing)
+            .clamp(self.min_limit as f64, self.max_limit as f64);
+        self.estimated_limit as usize
+    }
+}
+
+struct LimiterData {
+    limit: usize,
+    in_flight: usize,
+    algorithm: Box<dyn LimitAlgorithm>
+}
+
+/*
+   A concurrency limiter adapting the number of requests in flight to the health of the downstream.
+   Unlike rate limiters, it limits how many requests run at the same time, not how many start per interval.
+   Each acquired slot is held by a ConcurrencyGuard, the guard records the latency and the outcome of the request when dropped,
+   and the algorithm (Aimd, Gradient) moves the limit accordingly.
+   The implementation is thread-safe.
+ */
+pub struct AdaptiveConcurrencyLimiter {
+    data: Arc<Mutex<LimiterData>>,
+    condvar: Arc<Condvar>
+}
+
+impl Clone for AdaptiveConcurrencyLimiter {
+    fn clone(&self) -> Self {
+        AdaptiveConcurrencyLimiter {
+            data: Arc::clone(&self.data),
+            condvar: Arc::clone(&self.condvar)
+        }
+    }
+}
+
+impl AdaptiveConcurrencyLimiter {
+    pub fn new<A>(initial_limit: usize, algorithm: A) -> Self where A: LimitAlgorithm + 'static {
+        assert!(initial_limit > 0);
+
+        AdaptiveConcurrencyLimiter {
+            data: Arc::new(Mutex::new(LimiterData {
+                limit: initial_limit,
+                in_flight: 0,
+                algorithm: Box::new(algorithm)
+            })),
+            condvar: Arc::new(Condvar::new())
+        }
+    }
+
+    // non-blocking, None if the limit is reached
+    pub fn try_acquire(&self) -> Option<ConcurrencyGuard> {
+        self.acquire_timeout(Duration::ZERO)
+    }
+
+    // blocking up to the timeout until a request in flight completes
+    pub fn acquire_timeout(&self, timeout: Duration) -> Option<ConcurrencyGuard> {
+        let start = Instant::now();
+        let mut data = self.data.lock().unwrap();
+        while data.in_flight >= data.limit {
+            let wait_time = timeout.saturating_sub(start.elapsed());
+            if wait_time.is_zero() {
+                return None;
+            }
+            data = self.condvar.wait_timeout(data, wait_time).unwrap().0;
+        }
+        data.in_flight += 1;
+        Some(ConcurrencyGuard { limiter: self.clone(), start: Instant::now(), outcome: Outcome::Success })
+    }
+
+    pub fn limit(&self) -> usize {
+        self.data.lock().unwrap().limit
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.data.lock().unwrap().in_flight
+    }
+
+    fn complete(&self, rtt: Duration, outcome: Outcome) {
+        let mut data = self.data.lock().unwrap();
+        let (limit, in_flight) = (data.limit, data.in_flight);
+        data.limit = data.algorithm.update(limit, in_flight, rtt, outcome).max(1);
+        data.in_flight -= 1;
+        self.condvar.notify_all();
+    }
+}
+
+/*
+   A slot of a request in flight, released on drop.
+   The request is recorded as successful unless the outcome is set or the thread is panicking.
+ */
+pub struct ConcurrencyGuard {
+    limiter: AdaptiveConcurrencyLimiter,
+    start: Instant,
+    outcome: Outcome
+}
+
+impl ConcurrencyGuard {
+    pub fn set_outcome(&mut self, outcome: Outcome) {
+        self.outcome = outcome;
+    }
+}
+
+impl Drop for ConcurrencyGuard {
+    fn drop(&mut self) {
+        let outcome = if thread::panicking() { Outcome::Failure } else { self.outcome };
+        self.limiter.complete(self.start.elapsed(), outcome);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread::JoinHandle;
+    use super::*;
+
+    #[test]
+    fn test_limit_reached() {
+        let limiter = AdaptiveConcurrencyLimiter::new(2, Aimd::new(1, 2, 0.5, Duration::from_secs(1)));
+        let first = limiter.try_acquire().unwrap();
+        let _second = limiter.try_acquire().unwrap();
+        assert!(limiter.try_acquire().is_none());
+        assert_eq!(limiter.in_flight(), 2);
+
+        let l = limiter.clone();
+        let waiting = thread::spawn(move || l.acquire_timeout(Duration::from_millis(500)));
+        thread::sleep(Duration::from_millis(10));
+        drop(first);
+        let _third = waiting.join().unwrap().unwrap();
+        assert!(limiter.acquire_timeout(Duration::from_millis(10)).is_none());
+    }
+
+    #[test]
+    fn test_aimd() {
+        let limiter = AdaptiveConcurrencyLimiter::new(4, Aimd::new(1, 10, 0.5, Duration::from_millis(50)));
+        // the limit grows only while it is used
+        drop(limiter.try_acquire());
+        assert_eq!(limiter.limit(), 4);
+        for _ in 0..3 {
+            let guards: Vec<ConcurrencyGuard> = (0..4).map(|_| limiter.try_acquire().unwrap()).collect();
+            drop(guards);
+        }
+        assert!(limiter.limit() > 4);
+
+        let limit = limiter.limit();
+        limiter.try_acquire().unwrap().set_outcome(Outcome::Failure);
+        assert_eq!(limiter.limit(), limit / 2);
+        limiter.try_acquire().unwrap().set_outcome(Outcome::Ignored);
+        assert_eq!(limiter.limit(), limit / 2);
+
+        // a slow request is a failure
+        let guard = limiter.try_acquire().unwrap();
+        thread::sleep(Duration::from_millis(60));
+        drop(guard);
+        assert_eq!(limiter.limit(), (limit / 2 / 2).max(1));
+    }
+
+    #[test]
+    fn test_gradient() {
+        let mut gradient = Gradient::new(1, 100);
+        let mut limit = 20;
+        for _ in 0..50 {
+            limit = gradient.update(limit, limit, Duration::from_millis(10), Outcome::Success);
+        }
+        assert!(limit > 20);
+
+        // the latency goes up, requests queue downstream
+        let grown_limit = limit;
+        for _ in 0..20 {
+            limit = gradient.update(limit, limit, Duration::from_millis(100), Outcome::Success);
+        }
+        assert!(limit < grown_limit);
+
+        let slow_limit = limit;
+        limit = gradient.update(limit, limit, Duration::from_millis(10), Outcome::Failure);
+        assert!(limit < slow_limit);
+        assert_eq!(gradient.update(limit, limit, Duration::from_millis(10), Outcome::Ignored), limit);
+    }
+
+    #[test]
+    fn test_panic_records_failure() {
+        let limiter = AdaptiveConcurrencyLimiter::new(8, Aimd::new(1, 10, 0.5, Duration::from_secs(1)));
+        let l = limiter.clone();
+        let result = thread::spawn(move || {
+            let _guard = l.try_acquire().unwrap();
+            panic!("downstream failed");
+        }).join();
+
+        assert!(result.is_err());
+        assert_eq!(limiter.in_flight(), 0);
+        assert_eq!(limiter.limit(), 4);
+    }
+
+    #[test]
+    fn test_multiple_threads() {
+        let limiter = AdaptiveConcurrencyLimiter::new(4, Aimd::new(4, 4, 0.5, Duration::from_secs(1)));
+        let running = Arc::new(AtomicUsize::new(0));
+        let max_running = Arc::new(AtomicUsize::new(0));
+        let tasks: Vec<JoinHandle<()>> = (0..8).map(|_| (limiter.clone(), running.clone(), max_running.clone()))
+            .map(|(limiter, running, max_running)| {
+                thread::spawn(move || {
+                    for _ in 0..20 {
+                        let _guard = limiter.acquire_timeout(Duration::from_secs(5)).unwrap();
+                        let curr = running.fetch_add(1, Ordering::SeqCst) + 1;
+                        max_running.fetch_max(curr, Ordering::SeqCst);
+                        thread::sleep(Duration::from_millis(1));
+                        running.fetch_sub(1, Ordering::SeqCst);
+                    }
+                })
+            }).collect();
+
+        tasks.into_iter().for_each(|thread| {
+            thread.join().unwrap();
+        });
+        assert!(max_running.load(Ordering::SeqCst) <= 4);
+        assert_eq!(limiter.in_flight(), 0);
+    }
+
+}
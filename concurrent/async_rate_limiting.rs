@@ -0,0 +1,208 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use crate::delayed_queue::{DelayedQueue, ScheduledEntry};
+use crate::rate_limiting::{Acquisition, RateLimiter};
+
+struct Waiter {
+    id: u64,
+    waker: Waker
+}
+
+struct Waiters {
+    queue: VecDeque<Waiter>,
+    next_id: u64
+}
+
+impl Waiters {
+    fn wake_first(&self) {
+        if let Some(waiter) = self.queue.front() {
+            waiter.waker.wake_by_ref();
+        }
+    }
+}
+
+/*
+   An async front of a rate limiter (LeakyBucket, TokenBucket, ...) for use inside async executors.
+   acquire_async returns a future instead of blocking the thread, waiting futures are served in FIFO order:
+   only the first waiter tries to acquire, the others wait until it is granted or dropped.
+   Dropping a waiting future gives its turn to the next waiter.
+   Waiters are woken by a timer thread, so it works with any executor.
+   Only the async callers are queued, synchronous callers of the inner limiter compete with the first waiter.
+ */
+pub struct AsyncRateLimiter<L> {
+    limiter: L,
+    waiters: Arc<Mutex<Waiters>>
+}
+
+impl <L: Clone> Clone for AsyncRateLimiter<L> {
+    fn clone(&self) -> Self {
+        AsyncRateLimiter {
+            limiter: self.limiter.clone(),
+            waiters: Arc::clone(&self.waiters)
+        }
+    }
+}
+
+impl <L: RateLimiter> AsyncRateLimiter<L> {
+    pub fn new(limiter: L) -> Self {
+        AsyncRateLimiter {
+            limiter,
+            waiters: Arc::new(Mutex::new(Waiters { queue: VecDeque::new(), next_id: 0 }))
+        }
+    }
+
+    // resolves to Granted once the permits are acquired, or to Denied if they can never be granted
+    pub fn acquire_async(&self, permits: usize) -> Acquire<'_, L> {
+        Acquire { limiter: self, permits, id: None }
+    }
+
+    pub fn limiter(&self) -> &L {
+        &self.limiter
+    }
+}
+
+pub struct Acquire<'a, L> {
+    limiter: &'a AsyncRateLimiter<L>,
+    permits: usize,
+    // the position in the waiters queue, once queued
+    id: Option<u64>
+}
+
+impl <L: RateLimiter> Future for Acquire<'_, L> {
+    type Output = Acquisition;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let mut waiters = this.limiter.waiters.lock().unwrap();
+
+        let is_first = match this.id {
+            None => waiters.queue.is_empty(),
+            Some(id) => waiters.queue.front().map(|waiter| waiter.id) == Some(id)
+        };
+        if is_first {
+            match this.limiter.limiter.try_acquire(this.permits) {
+                Acquisition::Denied { retry_after } if retry_after != Duration::MAX => {
+                    wake_after(retry_after, cx.waker().clone());
+                },
+                acquisition => {
+                    if this.id.take().is_some() {
+                        waiters.queue.pop_front();
+                        waiters.wake_first();
+                    }
+                    return Poll::Ready(acquisition);
+                }
+            }
+        }
+
+        match this.id {
+            Some(id) => {
+                if let Some(waiter) = waiters.queue.iter_mut().find(|waiter| waiter.id == id) {
+                    waiter.waker.clone_from(cx.waker());
+                }
+            },
+            None => {
+                let id = waiters.next_id;
+                waiters.next_id += 1;
+                waiters.queue.push_back(Waiter { id, waker: cx.waker().clone() });
+                this.id = Some(id);
+            }
+        }
+        Poll::Pending
+    }
+}
+
+impl <L> Drop for Acquire<'_, L> {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            let mut waiters = self.limiter.waiters.lock().unwrap();
+            let was_first = waiters.queue.front().map(|waiter| waiter.id) == Some(id);
+            waiters.queue.retain(|waiter| waiter.id != id);
+            if was_first {
+                waiters.wake_first();
+            }
+        }
+    }
+}
+
+// wakes the waker after the delay from a single timer thread shared by all limiters
+fn wake_after(delay: Duration, waker: Waker) {
+    static TIMER: OnceLock<DelayedQueue<Waker>> = OnceLock::new();
+
+    let timer = TIMER.get_or_init(|| {
+        let queue: DelayedQueue<Waker> = DelayedQueue::new();
+        let timer_queue = queue.clone();
+        thread::Builder::new().name("rate-limiter-timer".to_string()).spawn(move || {
+            while let Some(scheduled_entry) = timer_queue.get() {
+                scheduled_entry.entry().wake();
+            }
+        }).unwrap();
+        queue
+    });
+    timer.offer(ScheduledEntry::of(waker, SystemTime::now() + delay));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+    use futures::executor::{block_on, LocalPool};
+    use futures::task::{noop_waker, LocalSpawnExt};
+    use crate::rate_limiting::{FixedWindow, LeakyBucket, TokenBucket};
+    use super::*;
+
+    #[test]
+    fn test_acquire_async() {
+        let limiter = AsyncRateLimiter::new(TokenBucket::new(10, Duration::from_millis(50), 10));
+        let start = Instant::now();
+        block_on(async {
+            assert!(limiter.acquire_async(10).await.is_granted());
+            assert!(limiter.acquire_async(5).await.is_granted());
+            // more permits than the bucket can ever hold
+            assert_eq!(limiter.acquire_async(11).await, Acquisition::Denied { retry_after: Duration::MAX });
+        });
+        assert!(start.elapsed().as_millis() >= 40);
+    }
+
+    #[test]
+    fn test_fifo() {
+        let limiter = AsyncRateLimiter::new(LeakyBucket::new(1, Duration::from_millis(20), 0, Duration::ZERO));
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let mut pool = LocalPool::new();
+
+        for idx in 0..5 {
+            let (limiter, order) = (limiter.clone(), Arc::clone(&order));
+            pool.spawner().spawn_local(async move {
+                limiter.acquire_async(1).await;
+                order.lock().unwrap().push(idx);
+            }).unwrap();
+        }
+        pool.run();
+
+        assert_eq!(*order.lock().unwrap(), vec![0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_cancellation() {
+        let limiter = AsyncRateLimiter::new(FixedWindow::new(1, Duration::from_millis(50)));
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        assert!(limiter.limiter().acquire());
+
+        let mut first = Box::pin(limiter.acquire_async(1));
+        let mut second = Box::pin(limiter.acquire_async(1));
+        assert!(first.as_mut().poll(&mut cx).is_pending());
+        assert!(second.as_mut().poll(&mut cx).is_pending());
+        assert_eq!(limiter.waiters.lock().unwrap().queue.len(), 2);
+
+        // the dropped future leaves the queue, the next one takes its turn
+        drop(first);
+        assert_eq!(limiter.waiters.lock().unwrap().queue.len(), 1);
+        assert_eq!(block_on(second), Acquisition::Granted);
+        assert!(limiter.waiters.lock().unwrap().queue.is_empty());
+    }
+
+}
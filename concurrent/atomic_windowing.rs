@@ -0,0 +1,333 @@
+use std::hint;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicI64, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::time::Duration;
+use crate::circuit_breaker::{buckets_since_epoch, Measurements, Windowing};
+use crate::clock::Clock;
+
+// Running totals of a window: a result is added when recorded and subtracted when it leaves the window.
+// Other threads may be between the two updates, so the totals are signed and read as at least 0.
+#[derive(Default)]
+struct Totals {
+    calls: AtomicI64,
+    failures: AtomicI64,
+    slow_calls: AtomicI64
+}
+
+impl Totals {
+    fn add(&self, calls: i64, failures: i64, slow_calls: i64) {
+        self.calls.fetch_add(calls, Ordering::AcqRel);
+        self.failures.fetch_add(failures, Ordering::AcqRel);
+        self.slow_calls.fetch_add(slow_calls, Ordering::AcqRel);
+    }
+
+    fn load(&self) -> Measurements {
+        Measurements {
+            calls: self.calls.load(Ordering::Acquire).max(0) as u64,
+            failures: self.failures.load(Ordering::Acquire).max(0) as u64,
+            slow_calls: self.slow_calls.load(Ordering::Acquire).max(0) as u64
+        }
+    }
+}
+
+// slot flags, an empty slot is 0
+const RECORDED: u8 = 1;
+const FAILED: u8 = 2;
+const SLOW: u8 = 4;
+
+// (calls, failures, slow calls) of a slot
+fn counts(slot: u8) -> (i64, i64, i64) {
+    ((slot & RECORDED != 0) as i64, (slot & FAILED != 0) as i64, (slot & SLOW != 0) as i64)
+}
+
+/*
+   A lock-free count-based sliding window: a ring of N atomic slots and the running totals of the window.
+   A result is swapped into the next slot and the totals are adjusted by the difference to the result it replaced,
+   so neither recording nor reading scans the slots.
+   Under contention the measurements may briefly lag behind the slots, they agree once the writers are done.
+ */
+pub struct AtomicWindowingCount {
+    slots: Arc<Vec<AtomicU8>>,
+    next: Arc<AtomicUsize>,
+    totals: Arc<Totals>
+}
+
+impl Clone for AtomicWindowingCount {
+    fn clone(&self) -> Self {
+        AtomicWindowingCount {
+            slots: Arc::clone(&self.slots),
+            next: Arc::clone(&self.next),
+            totals: Arc::clone(&self.totals)
+        }
+    }
+}
+
+impl AtomicWindowingCount {
+    pub fn new(measurements: usize) -> Self {
+        assert!(measurements > 0);
+
+        AtomicWindowingCount {
+            slots: Arc::new((0..measurements).map(|_| AtomicU8::new(0)).collect()),
+            next: Arc::new(AtomicUsize::new(0)),
+            totals: Arc::new(Totals::default())
+        }
+    }
+}
+
+impl Windowing for AtomicWindowingCount {
+    fn register_result(&self, was_successful: bool, was_slow: bool) -> Measurements {
+        let slot = RECORDED | if was_successful { 0 } else { FAILED } | if was_slow { SLOW } else { 0 };
+        let idx = self.next.fetch_add(1, Ordering::Relaxed) % self.slots.len();
+        let (old_calls, old_failures, old_slow_calls) = counts(self.slots[idx].swap(slot, Ordering::AcqRel));
+        let (calls, failures, slow_calls) = counts(slot);
+        self.totals.add(calls - old_calls, failures - old_failures, slow_calls - old_slow_calls);
+        self.totals.load()
+    }
+
+    fn measurements(&self) -> Measurements {
+        self.totals.load()
+    }
+
+    fn reset(&self) {
+        for slot in self.slots.iter() {
+            let (calls, failures, slow_calls) = counts(slot.swap(0, Ordering::AcqRel));
+            self.totals.add(-calls, -failures, -slow_calls);
+        }
+    }
+
+    fn capacity(&self) -> Option<u64> {
+        Some(self.slots.len() as u64)
+    }
+}
+
+// the tag of a bucket being cleared
+const CLEARING: u64 = u64::MAX;
+
+#[derive(Default)]
+struct Bucket {
+    // the bucket since EPOCH the counts belong to
+    epoch: AtomicU64,
+    calls: AtomicU64,
+    failures: AtomicU64,
+    slow_calls: AtomicU64
+}
+
+/*
+   A lock-free time-based sliding window: a ring of buckets and the running totals of the window.
+   The thread moving the head to a new bucket clears the buckets which left the window and subtracts them from the totals,
+   so the buckets are cleared once each instead of being scanned on every call.
+   Every bucket is tagged with its epoch and a result is recorded only once the tag matches the current epoch,
+   the threads recording into a bucket being cleared wait for it, so no result of the current window is lost.
+   The window is split into buckets of 1 second by default, shorter buckets let the results expire more smoothly.
+ */
+pub struct AtomicWindowingTime {
+    buckets: Arc<Vec<Bucket>>,
+    // the latest bucket since EPOCH, the buckets before it are cleared
+    head: Arc<AtomicU64>,
+    totals: Arc<Totals>,
+    bucket: Duration,
+    clock: Arc<dyn Clock>
+}
+
+impl Clone for AtomicWindowingTime {
+    fn clone(&self) -> Self {
+        AtomicWindowingTime {
+            buckets: Arc::clone(&self.buckets),
+            head: Arc::clone(&self.head),
+            totals: Arc::clone(&self.totals),
+            bucket: self.bucket,
+            clock: Arc::clone(&self.clock)
+        }
+    }
+}
+
+impl AtomicWindowingTime {
+    pub fn new(seconds: usize, clock: Arc<dyn Clock>) -> Self {
+        Self::with_buckets(Duration::from_secs(seconds as u64), Duration::from_secs(1), clock)
+    }
+
+    pub fn with_buckets(window: Duration, bucket: Duration, clock: Arc<dyn Clock>) -> Self {
+        assert!(!bucket.is_zero() && window >= bucket);
+
+        let buckets = window.as_nanos().div_ceil(bucket.as_nanos()) as usize;
+        AtomicWindowingTime {
+            buckets: Arc::new((0..buckets).map(|_| Bucket::default()).collect()),
+            head: Arc::new(AtomicU64::new(buckets_since_epoch(clock.as_ref(), bucket))),
+            totals: Arc::new(Totals::default()),
+            bucket,
+            clock
+        }
+    }
+
+    // clears the bucket and moves its tag to the one next_tag returns for the current tag, does nothing for None
+    fn clear<F>(&self, bucket: &Bucket, next_tag: F) where F: Fn(u64) -> Option<u64> {
+        loop {
+            let tag = bucket.epoch.load(Ordering::Acquire);
+            if tag == CLEARING {
+                hint::spin_loop();
+                continue;
+            }
+            let next_tag = match next_tag(tag) {
+                Some(next_tag) => next_tag,
+                None => return
+            };
+            if bucket.epoch.compare_exchange_weak(tag, CLEARING, Ordering::AcqRel, Ordering::Acquire).is_ok() {
+                let calls = bucket.calls.swap(0, Ordering::AcqRel) as i64;
+                let failures = bucket.failures.swap(0, Ordering::AcqRel) as i64;
+                let slow_calls = bucket.slow_calls.swap(0, Ordering::AcqRel) as i64;
+                self.totals.add(-calls, -failures, -slow_calls);
+                bucket.epoch.store(next_tag, Ordering::Release);
+                return;
+            }
+        }
+    }
+
+    // starts the bucket of the epoch again unless it already belongs to it or to a later one
+    fn renew(&self, epoch: u64) -> &Bucket {
+        let bucket = &self.buckets[(epoch % self.buckets.len() as u64) as usize];
+        self.clear(bucket, |tag| (tag < epoch).then_some(epoch));
+        bucket
+    }
+
+    // moves the head to the current bucket, returns the current bucket since EPOCH
+    fn advance(&self) -> u64 {
+        let now = buckets_since_epoch(self.clock.as_ref(), self.bucket);
+        let len = self.buckets.len() as u64;
+        let mut head = self.head.load(Ordering::Acquire);
+        while now > head {
+            match self.head.compare_exchange_weak(head, now, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => {
+                    // the buckets after the old head start again, all of them if the whole window has passed
+                    let first = (head + 1).max((now + 1).saturating_sub(len));
+                    (first..=now).for_each(|epoch| { self.renew(epoch); });
+                    return now;
+                },
+                Err(current) => head = current
+            }
+        }
+        head
+    }
+}
+
+impl Windowing for AtomicWindowingTime {
+    fn register_result(&self, was_successful: bool, was_slow: bool) -> Measurements {
+        // the thread moving the head might not have renewed the bucket yet
+        let bucket = self.renew(self.advance());
+        bucket.calls.fetch_add(1, Ordering::AcqRel);
+        bucket.failures.fetch_add(!was_successful as u64, Ordering::AcqRel);
+        bucket.slow_calls.fetch_add(was_slow as u64, Ordering::AcqRel);
+        self.totals.add(1, !was_successful as i64, was_slow as i64);
+        self.totals.load()
+    }
+
+    fn measurements(&self) -> Measurements {
+        self.advance();
+        self.totals.load()
+    }
+
+    fn reset(&self) {
+        self.buckets.iter().for_each(|bucket| self.clear(bucket, Some));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use crate::circuit_breaker::{CircuitBreaker, Error, State, WindowingCount, WindowingTime};
+    use crate::clock::MockClock;
+    use super::*;
+
+    #[test]
+    fn test_atomic_windowing_count() {
+        let windowing = AtomicWindowingCount::new(4);
+        let expected = WindowingCount::new(4);
+        for idx in 0..10 {
+            let (was_successful, was_slow) = (idx % 3 != 0, idx % 4 == 0);
+            assert_eq!(windowing.register_result(was_successful, was_slow), expected.register_result(was_successful, was_slow));
+        }
+        windowing.reset();
+        assert_eq!(windowing.measurements(), Measurements::default());
+    }
+
+    #[test]
+    fn test_atomic_windowing_count_threads() {
+        let windowing = AtomicWindowingCount::new(100);
+        let threads: Vec<_> = (0..8).map(|idx| {
+            let windowing = windowing.clone();
+            thread::spawn(move || (0..1000).for_each(|i| { windowing.register_result((idx + i) % 2 == 0, i % 3 == 0); }))
+        }).collect();
+        threads.into_iter().for_each(|handle| handle.join().unwrap());
+
+        // the totals agree with the slots once the writers are done
+        let (calls, failures, slow_calls) = windowing.slots.iter()
+            .map(|slot| counts(slot.load(Ordering::Acquire)))
+            .fold((0, 0, 0), |(c1, f1, s1), (c2, f2, s2)| (c1 + c2, f1 + f2, s1 + s2));
+        assert_eq!(windowing.measurements(), Measurements { calls: calls as u64, failures: failures as u64, slow_calls: slow_calls as u64 });
+        assert_eq!(calls, 100);
+    }
+
+    #[test]
+    fn test_sub_second_buckets() {
+        let clock = MockClock::new();
+        // align the clock to a bucket
+        let offset = Duration::from_nanos(100_000_000 - (buckets_since_epoch(&clock, Duration::from_nanos(1)) % 100_000_000));
+        clock.advance(offset);
+        let windowing = AtomicWindowingTime::with_buckets(Duration::from_secs(1), Duration::from_millis(100), Arc::new(clock.clone()));
+        let expected = WindowingTime::with_buckets(Duration::from_secs(1), Duration::from_millis(100), Arc::new(clock.clone()));
+
+        let register = |was_successful: bool| {
+            let measurements = windowing.register_result(was_successful, false);
+            assert_eq!(measurements, expected.register_result(was_successful, false));
+            measurements
+        };
+        (0..5).for_each(|_| { register(false); });
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(register(true), Measurements { calls: 6, failures: 5, slow_calls: 0 });
+        // the failures expire 1 second after their bucket started
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(register(true), Measurements { calls: 2, failures: 0, slow_calls: 0 });
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(windowing.measurements(), Measurements::default());
+        assert_eq!(expected.measurements(), Measurements::default());
+    }
+
+    #[test]
+    fn test_atomic_windowing_time_threads() {
+        let clock = MockClock::new();
+        let windowing = AtomicWindowingTime::with_buckets(Duration::from_secs(1), Duration::from_millis(10), Arc::new(clock.clone()));
+        let threads: Vec<_> = (0..8).map(|idx| {
+            let (windowing, clock) = (windowing.clone(), clock.clone());
+            thread::spawn(move || (0..1000).for_each(|i| {
+                windowing.register_result((idx + i) % 2 == 0, false);
+                if i % 100 == 0 {
+                    clock.advance(Duration::from_millis(7));
+                }
+            }))
+        }).collect();
+        threads.into_iter().for_each(|handle| handle.join().unwrap());
+
+        let calls: u64 = windowing.buckets.iter().map(|bucket| bucket.calls.load(Ordering::Acquire)).sum();
+        let failures: u64 = windowing.buckets.iter().map(|bucket| bucket.failures.load(Ordering::Acquire)).sum();
+        assert_eq!(windowing.measurements(), Measurements { calls, failures, slow_calls: 0 });
+        // the clock moved 560ms, so no result has left the window and none was lost to a bucket being cleared
+        assert_eq!((calls, failures), (8000, 4000));
+    }
+
+    #[test]
+    fn test_circuit_breaker() {
+        let clock = MockClock::new();
+        let cb = CircuitBreaker::with_windowing(AtomicWindowingCount::new(10), 0.5, Duration::from_secs(60), Arc::new(clock.clone()))
+            .minimum_calls(4);
+
+        for _ in 0..2 {
+            cb.execute(|| { if 1 > 0 { Ok(1) } else { Err("oops") } });
+            cb.execute(|| { if 1 > 0 { Err("oops") } else { Ok(1) } });
+        }
+        assert_eq!(cb.execute(|| { if 1 > 0 { Ok(1) } else { Err("oops") } }), Err(Error::Rejected));
+        clock.advance(Duration::from_secs(61));
+        cb.execute(|| { if 1 > 0 { Ok(1) } else { Err("oops") } });
+        assert_eq!(cb.state(), State::Closed);
+        assert_eq!(cb.measurements(), Measurements::default());
+    }
+
+}
@@ -0,0 +1,113 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/*
+   Exponential backoff: the delay grows by multiplier with every attempt and is capped at max_delay.
+   jitter spreads the delay randomly by up to ±jitter of its value,
+   so the clients which failed together do not come back together.
+ */
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ExponentialBackoff {
+    initial_delay: Duration,
+    multiplier: f64,
+    max_delay: Duration,
+    jitter: f64
+}
+
+impl ExponentialBackoff {
+    pub fn new(initial_delay: Duration, multiplier: f64, max_delay: Duration) -> Self {
+        assert!(multiplier >= 1.0);
+
+        ExponentialBackoff {
+            initial_delay,
+            multiplier,
+            max_delay,
+            jitter: 0.0
+        }
+    }
+
+    pub fn with_jitter(mut self, jitter: f64) -> Self {
+        assert!((0.0..=1.0).contains(&jitter));
+
+        self.jitter = jitter;
+        self
+    }
+
+    // the delay after the given number of failed attempts, initial_delay after the first one
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let delay = self.initial_delay.as_secs_f64() * self.multiplier.powi(attempt.saturating_sub(1).min(i32::MAX as u32) as i32);
+        let delay = delay * (1.0 + self.jitter * (2.0 * random() - 1.0));
+        Duration::try_from_secs_f64(delay).unwrap_or(Duration::MAX).min(self.max_delay)
+    }
+}
+
+// The delay between attempts
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Backoff {
+    Fixed(Duration),
+    Exponential(ExponentialBackoff),
+    // a random delay between base and 3 times the previous delay, capped at max
+    DecorrelatedJitter { base: Duration, max: Duration }
+}
+
+impl Backoff {
+    // the delay after the given number of failed attempts, previous is the last delay
+    pub fn delay(&self, attempt: u32, previous: Duration) -> Duration {
+        match *self {
+            Backoff::Fixed(delay) => delay,
+            Backoff::Exponential(backoff) => backoff.delay(attempt),
+            Backoff::DecorrelatedJitter { base, max } => {
+                let base = base.as_secs_f64();
+                let upper = previous.as_secs_f64().max(base) * 3.0;
+                Duration::try_from_secs_f64(base + random() * (upper - base)).unwrap_or(Duration::MAX).min(max)
+            }
+        }
+    }
+}
+
+// a random number in [0, 1), good enough for jitter
+pub fn random() -> f64 {
+    static SEQ: AtomicU64 = AtomicU64::new(0);
+
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(SEQ.fetch_add(1, Ordering::Relaxed));
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exponential_backoff() {
+        let backoff = ExponentialBackoff::new(Duration::from_secs(1), 2.0, Duration::from_secs(10));
+        let delays: Vec<u64> = (1..7).map(|attempt| backoff.delay(attempt).as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 8, 10, 10]);
+        assert_eq!(backoff.delay(u32::MAX), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_jitter() {
+        let backoff = ExponentialBackoff::new(Duration::from_secs(4), 2.0, Duration::from_secs(10)).with_jitter(0.5);
+        let delays: Vec<Duration> = (0..100).map(|_| backoff.delay(1)).collect();
+        assert!(delays.iter().all(|delay| *delay >= Duration::from_secs(2) && *delay <= Duration::from_secs(6)));
+        assert!(delays.iter().any(|delay| *delay != delays[0]));
+        // the cap applies after the jitter
+        assert!((0..100).all(|_| backoff.delay(2) <= Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn test_decorrelated_jitter() {
+        let backoff = Backoff::DecorrelatedJitter { base: Duration::from_secs(1), max: Duration::from_secs(20) };
+        let mut previous = Duration::ZERO;
+        for attempt in 1..50 {
+            let delay = backoff.delay(attempt, previous);
+            assert!(delay >= Duration::from_secs(1) && delay <= (previous * 3).clamp(Duration::from_secs(3), Duration::from_secs(20)));
+            previous = delay;
+        }
+        assert_eq!(Backoff::Fixed(Duration::from_secs(1)).delay(10, Duration::from_secs(5)), Duration::from_secs(1));
+    }
+
+}
@@ -0,0 +1,283 @@
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+use std::time::Duration;
+use crate::circuit_breaker::Error;
+use crate::clock::{Clock, SystemClock};
+use crate::thread_pool::ThreadPool;
+
+/*
+   A semaphore bulkhead limits the number of concurrent calls of a dependency.
+   A call waits up to max_wait for a free slot, then it is rejected with Error::BulkheadFull,
+   so a slow dependency cannot take all the threads of the caller.
+ */
+pub struct SemaphoreBulkhead {
+    // calls in flight
+    in_flight: Arc<Mutex<usize>>,
+    condvar: Arc<Condvar>,
+    max_concurrent_calls: usize,
+    max_wait: Duration,
+    clock: Arc<dyn Clock>
+}
+
+impl Clone for SemaphoreBulkhead {
+    fn clone(&self) -> Self {
+        SemaphoreBulkhead {
+            in_flight: Arc::clone(&self.in_flight),
+            condvar: Arc::clone(&self.condvar),
+            max_concurrent_calls: self.max_concurrent_calls,
+            max_wait: self.max_wait,
+            clock: Arc::clone(&self.clock)
+        }
+    }
+}
+
+// frees the slot even if the call panics
+struct Permit<'a> {
+    bulkhead: &'a SemaphoreBulkhead
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        *self.bulkhead.in_flight.lock().unwrap() -= 1;
+        self.bulkhead.condvar.notify_one();
+    }
+}
+
+impl SemaphoreBulkhead {
+    pub fn new(max_concurrent_calls: usize, max_wait: Duration) -> Self {
+        Self::with_clock(max_concurrent_calls, max_wait, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(max_concurrent_calls: usize, max_wait: Duration, clock: Arc<dyn Clock>) -> Self {
+        assert!(max_concurrent_calls > 0);
+
+        SemaphoreBulkhead {
+            in_flight: Arc::new(Mutex::new(0)),
+            condvar: Arc::new(Condvar::new()),
+            max_concurrent_calls,
+            max_wait,
+            clock
+        }
+    }
+
+    pub fn in_flight(&self) -> usize {
+        *self.in_flight.lock().unwrap()
+    }
+
+    fn acquire(&self) -> Option<Permit<'_>> {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        let start = self.clock.now();
+        loop {
+            if *in_flight < self.max_concurrent_calls {
+                *in_flight += 1;
+                return Some(Permit { bulkhead: self });
+            }
+            let wait_timeout = self.max_wait.saturating_sub(self.clock.now().saturating_duration_since(start));
+            if wait_timeout.is_zero() {
+                return None;
+            }
+            in_flight = self.condvar.wait_timeout(in_flight, self.clock.block_timeout(wait_timeout)).unwrap().0;
+        }
+    }
+
+    pub fn execute<F, R, E>(&self, f: F) -> Result<R, Error<E>> where F: FnOnce() -> Result<R, E> {
+        let _permit = self.acquire().ok_or(Error::BulkheadFull)?;
+        f().map_err(|e| Error::Custom(e))
+    }
+}
+
+/*
+   A thread pool bulkhead runs the calls on its own pool of threads.
+   Calls wait in a queue of queue_capacity while all the threads are busy,
+   the calls above it are rejected with Error::BulkheadFull.
+   After a shutdown the calls are rejected with Error::Rejected, the queued calls are dropped without running.
+ */
+pub struct ThreadPoolBulkhead {
+    pool: ThreadPool,
+    // calls running or waiting in the queue
+    pending: Arc<AtomicUsize>,
+    capacity: usize
+}
+
+impl Clone for ThreadPoolBulkhead {
+    fn clone(&self) -> Self {
+        ThreadPoolBulkhead {
+            pool: self.pool.clone(),
+            pending: Arc::clone(&self.pending),
+            capacity: self.capacity
+        }
+    }
+}
+
+// frees the pending slot when the call completes or is dropped by a shutdown
+struct PendingSlot {
+    pending: Arc<AtomicUsize>
+}
+
+impl Drop for PendingSlot {
+    fn drop(&mut self) {
+        self.pending.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl ThreadPoolBulkhead {
+    pub fn new(threads: usize, queue_capacity: usize) -> Self {
+        assert!(threads > 0);
+
+        ThreadPoolBulkhead {
+            pool: ThreadPool::new(threads),
+            pending: Arc::new(AtomicUsize::new(0)),
+            capacity: threads + queue_capacity
+        }
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.load(Ordering::Acquire)
+    }
+
+    // the result is received once the call completes, a panic of the call is passed to the receiver
+    // the receiver is disconnected if the call is dropped by a shutdown
+    pub fn submit<F, R, E>(&self, f: F) -> Result<Receiver<thread::Result<Result<R, E>>>, Error<E>>
+        where F: FnOnce() -> Result<R, E> + Send + 'static, R: Send + 'static, E: Send + 'static {
+        if self.pending.fetch_update(Ordering::AcqRel, Ordering::Acquire, |pending| (pending < self.capacity).then_some(pending + 1)).is_err() {
+            return Err(Error::BulkheadFull);
+        }
+        let slot = PendingSlot { pending: Arc::clone(&self.pending) };
+        let (sender, receiver) = channel();
+        let submitted = self.pool.try_execute(move || {
+            // the worker survives a panic of the call
+            let result = panic::catch_unwind(AssertUnwindSafe(f));
+            drop(slot);
+            let _ = sender.send(result);
+        });
+        if submitted { Ok(receiver) } else { Err(Error::Rejected) }
+    }
+
+    // waits for the call on the current thread
+    pub fn execute<F, R, E>(&self, f: F) -> Result<R, Error<E>>
+        where F: FnOnce() -> Result<R, E> + Send + 'static, R: Send + 'static, E: Send + 'static {
+        match self.submit(f)?.recv() {
+            Ok(Ok(result)) => result.map_err(|e| Error::Custom(e)),
+            Ok(Err(payload)) => panic::resume_unwind(payload),
+            // the queued call was dropped by a shutdown
+            Err(_) => Err(Error::Rejected)
+        }
+    }
+
+    pub fn shutdown(&self) {
+        self.pool.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Barrier;
+    use crate::clock::MockClock;
+    use super::*;
+
+    #[test]
+    fn test_semaphore_bulkhead() {
+        let bulkhead = SemaphoreBulkhead::new(2, Duration::from_millis(10));
+        let barrier = Arc::new(Barrier::new(3));
+
+        let threads: Vec<_> = (0..2).map(|idx| {
+            let (bulkhead, barrier) = (bulkhead.clone(), Arc::clone(&barrier));
+            thread::spawn(move || bulkhead.execute(|| {
+                barrier.wait();
+                barrier.wait();
+                if 1 > 0 { Ok(idx) } else { Err("oops") }
+            }))
+        }).collect();
+
+        // both slots are taken
+        barrier.wait();
+        assert_eq!(bulkhead.in_flight(), 2);
+        assert_eq!(bulkhead.execute(|| { if 1 > 0 { Ok(2) } else { Err("oops") } }), Err(Error::BulkheadFull));
+        barrier.wait();
+
+        let results: Vec<_> = threads.into_iter().map(|handle| handle.join().unwrap()).collect();
+        assert_eq!(results, vec![Ok(0), Ok(1)]);
+        assert_eq!(bulkhead.execute(|| { if 1 > 0 { Ok(2) } else { Err("oops") } }), Ok(2));
+        assert_eq!(bulkhead.in_flight(), 0);
+    }
+
+    #[test]
+    fn test_semaphore_bulkhead_max_wait() {
+        let clock = MockClock::new();
+        let bulkhead = SemaphoreBulkhead::with_clock(1, Duration::from_secs(5), Arc::new(clock.clone()));
+
+        let result = bulkhead.execute(|| bulkhead.execute(|| { if 1 > 0 { Ok(1) } else { Err("oops") } }));
+        assert_eq!(result, Err(Error::Custom(Error::BulkheadFull)));
+        // the call waited for a slot before it was rejected
+        assert_eq!(clock.elapsed(), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_thread_pool_bulkhead() {
+        let bulkhead = ThreadPoolBulkhead::new(2, 1);
+        let barrier = Arc::new(Barrier::new(3));
+
+        let receivers: Vec<_> = (0..3).map(|idx| {
+            let barrier = Arc::clone(&barrier);
+            bulkhead.submit(move || {
+                if idx < 2 {
+                    barrier.wait();
+                }
+                if 1 > 0 { Ok(idx) } else { Err("oops") }
+            }).unwrap()
+        }).collect();
+
+        // 2 calls are running and 1 is queued
+        assert_eq!(bulkhead.pending(), 3);
+        assert_eq!(bulkhead.execute(|| { if 1 > 0 { Ok(3) } else { Err("oops") } }), Err(Error::BulkheadFull));
+        barrier.wait();
+
+        let results: Vec<_> = receivers.into_iter().map(|receiver| receiver.recv().unwrap().unwrap()).collect();
+        assert_eq!(results, vec![Ok(0), Ok(1), Ok(2)]);
+        assert_eq!(bulkhead.execute(|| { if 1 > 0 { Ok(3) } else { Err("oops") } }), Ok(3));
+    }
+
+    #[test]
+    fn test_thread_pool_bulkhead_shutdown() {
+        let bulkhead = ThreadPoolBulkhead::new(1, 1);
+        let barrier = Arc::new(Barrier::new(2));
+        let running = {
+            let barrier = Arc::clone(&barrier);
+            bulkhead.submit(move || {
+                barrier.wait();
+                barrier.wait();
+                if 1 > 0 { Ok(0) } else { Err("oops") }
+            }).unwrap()
+        };
+        barrier.wait();
+        let queued = {
+            let bulkhead = bulkhead.clone();
+            thread::spawn(move || bulkhead.execute(|| { if 1 > 0 { Ok(1) } else { Err("oops") } }))
+        };
+        while bulkhead.pending() < 2 {
+            thread::yield_now();
+        }
+
+        bulkhead.shutdown();
+        bulkhead.shutdown();
+        barrier.wait();
+
+        // the running call completes, the queued one is dropped
+        assert_eq!(running.recv().unwrap().unwrap(), Ok(0));
+        assert_eq!(queued.join().unwrap(), Err(Error::Rejected));
+        assert_eq!(bulkhead.pending(), 0);
+        assert_eq!(bulkhead.execute(|| { if 1 > 0 { Ok(2) } else { Err("oops") } }), Err(Error::Rejected));
+    }
+
+    #[test]
+    #[should_panic(expected = "boom")]
+    fn test_thread_pool_bulkhead_panic() {
+        let bulkhead = ThreadPoolBulkhead::new(1, 0);
+        let _ = bulkhead.execute(|| -> Result<(), &str> { panic!("boom") });
+    }
+
+}
@@ -1,55 +1,155 @@
+use std::any::Any;
 use std::fmt::Debug;
-use std::sync::{Arc, Mutex};
-use std::thread;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::time::{Duration, Instant, SystemTime};
 use crossbeam::atomic::AtomicCell;
+use crate::backoff::ExponentialBackoff;
+use crate::clock::{Clock, SystemClock};
+use crate::time_limiter::{CancellationToken, TimeLimiter};
 
 #[derive(Debug, PartialEq)]
 pub enum Error<E> {
     Custom(E),
-    Rejected
+    Rejected,
+    // no permit from the rate limiter of a pipeline
+    RateLimited { retry_after: Duration },
+    // the bulkhead has no room for the call
+    BulkheadFull,
+    // the call did not complete within the time limit
+    Timeout
 }
 
-#[derive(Copy, Clone, Eq, PartialEq)]
-enum State {
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum State {
     Open(Instant),
     HalfOpen,
-    Closed
+    Closed,
+    // rejects all calls until closed manually
+    ForcedOpen,
+    // permits all calls without recording them
+    Disabled
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    StateTransition { from: State, to: State, time: SystemTime, failure_rate: f32 },
+    Success { elapsed: Duration, slow: bool },
+    Error { elapsed: Duration, slow: bool },
+    // the call is not recorded
+    Ignored { elapsed: Duration },
+    Rejected
+}
+
+type Subscriber = Box<dyn Fn(&Event) + Send + Sync>;
+
+// None if the value is not of the type the predicate was configured for
+type Predicate = Arc<dyn Fn(&dyn Any) -> Option<bool> + Send + Sync>;
+
+fn predicate<T: 'static, P>(predicate: P) -> Predicate where P: Fn(&T) -> bool + Send + Sync + 'static {
+    Arc::new(move |value: &dyn Any| value.downcast_ref::<T>().map(&predicate))
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum CallOutcome {
+    Success,
+    Failure,
+    Ignored
+}
+
+// The results recorded in a window
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct Measurements {
+    pub calls: u64,
+    pub failures: u64,
+    // calls slower than the slow call duration, successful or not
+    pub slow_calls: u64
+}
+
+impl Measurements {
+    pub fn failure_rate(&self) -> f32 {
+        if self.calls == 0 { 0.0 } else { self.failures as f32 / self.calls as f32 }
+    }
+
+    pub fn slow_call_rate(&self) -> f32 {
+        if self.calls == 0 { 0.0 } else { self.slow_calls as f32 / self.calls as f32 }
+    }
 }
 
 pub trait Windowing {
-    fn register_result(&self, was_successful: bool) -> (u64, u64);
+    fn register_result(&self, was_successful: bool, was_slow: bool) -> Measurements;
+    fn measurements(&self) -> Measurements;
     fn reset(&self);
+
+    // the number of results the window holds, None if it is not bounded by count
+    fn capacity(&self) -> Option<u64> {
+        None
+    }
+}
+
+impl <W: Windowing + ?Sized> Windowing for Box<W> {
+    fn register_result(&self, was_successful: bool, was_slow: bool) -> Measurements {
+        (**self).register_result(was_successful, was_slow)
+    }
+
+    fn measurements(&self) -> Measurements {
+        (**self).measurements()
+    }
+
+    fn reset(&self) {
+        (**self).reset()
+    }
+
+    fn capacity(&self) -> Option<u64> {
+        (**self).capacity()
+    }
 }
 
+// (was successful, was slow), None if no call was recorded in the slot yet
+type CountSlot = Option<(bool, bool)>;
+
 // Count-based sliding window implemented with a circular array of N slots
-struct WindowingCount {
-    // results slots: true (successful execution), false (unsuccessful execution)
-    // counter iterating over the circular array
-    slots: Arc<Mutex<(Vec<bool>, usize)>>
+pub struct WindowingCount {
+    // results slots and the counter iterating over the circular array
+    slots: Arc<Mutex<(Vec<CountSlot>, usize)>>
 }
 
 impl WindowingCount {
-    fn new(measurements: usize) -> Self {
+    pub fn new(measurements: usize) -> Self {
         WindowingCount {
-            slots: Arc::new(Mutex::new((vec![true; measurements], 0)))
+            slots: Arc::new(Mutex::new((vec![None; measurements], 0)))
         }
     }
+
+    fn sum(slots: &[CountSlot]) -> Measurements {
+        slots.iter().flatten().fold(Measurements::default(), |measurements, &(was_successful, was_slow)| Measurements {
+            calls: measurements.calls + 1,
+            failures: measurements.failures + !was_successful as u64,
+            slow_calls: measurements.slow_calls + was_slow as u64
+        })
+    }
 }
 
 impl Windowing for WindowingCount {
-    fn register_result(&self, was_successful: bool) -> (u64, u64) {
+    fn register_result(&self, was_successful: bool, was_slow: bool) -> Measurements {
         let (ref mut slots, ref mut counter) = *self.slots.lock().unwrap();
-        slots[*counter] = was_successful;
+        slots[*counter] = Some((was_successful, was_slow));
         *counter = (*counter + 1) % slots.len();
+        Self::sum(slots)
+    }
 
-        let successCount = slots.iter().filter(|&&was_successful| was_successful).count();
-        (successCount as u64, (slots.len() - successCount) as u64)
+    fn measurements(&self) -> Measurements {
+        let (ref slots, _) = *self.slots.lock().unwrap();
+        Self::sum(slots)
     }
 
     fn reset(&self) {
         let (ref mut slots, _) = *self.slots.lock().unwrap();
-        slots.fill(true);
+        slots.fill(None);
+    }
+
+    fn capacity(&self) -> Option<u64> {
+        Some(self.slots.lock().unwrap().0.len() as u64)
     }
 }
 
@@ -61,87 +161,144 @@ impl Clone for WindowingCount {
     }
 }
 
-struct WindowingTime {
-    // (seconds since EPOCH, success count, failure count)
-    slots: Arc<Mutex<Vec<(u64, u64, u64)>>>
+// (buckets since EPOCH, success count, failure count, slow count)
+type TimeSlot = (u64, u64, u64, u64);
+
+// Time-based sliding window implemented with a circular array of buckets, 1 second each by default
+pub struct WindowingTime {
+    slots: Arc<Mutex<Vec<TimeSlot>>>,
+    bucket: Duration,
+    clock: Arc<dyn Clock>
+}
+
+// the number of buckets of the given duration since EPOCH
+pub(crate) fn buckets_since_epoch(clock: &dyn Clock, bucket: Duration) -> u64 {
+    (clock.system_time().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_nanos() / bucket.as_nanos()) as u64
 }
 
 impl WindowingTime {
-    fn new(seconds: usize) -> Self {
+    pub fn new(seconds: usize, clock: Arc<dyn Clock>) -> Self {
+        Self::with_buckets(Duration::from_secs(seconds as u64), Duration::from_secs(1), clock)
+    }
+
+    // the window is split into buckets of the given duration, the oldest bucket expires as a whole
+    pub fn with_buckets(window: Duration, bucket: Duration, clock: Arc<dyn Clock>) -> Self {
+        assert!(!bucket.is_zero() && window >= bucket);
+
+        let buckets = window.as_nanos().div_ceil(bucket.as_nanos()) as usize;
         WindowingTime {
-            slots: Arc::new(Mutex::new(vec![(0, 0, 0); seconds]))
+            slots: Arc::new(Mutex::new(vec![(0, 0, 0, 0); buckets])),
+            bucket,
+            clock
         }
     }
+
+    // sums up the slots which are not older than the window
+    fn sum(slots: &[TimeSlot], buckets_since_epoch: u64) -> Measurements {
+        slots.iter().filter(|&&(last_since_epoch, ..)| buckets_since_epoch.saturating_sub(last_since_epoch) < slots.len() as u64)
+            .fold(Measurements::default(), |measurements, &(_, success_count, failure_count, slow_count)| Measurements {
+                calls: measurements.calls + success_count + failure_count,
+                failures: measurements.failures + failure_count,
+                slow_calls: measurements.slow_calls + slow_count
+            })
+    }
 }
 
 impl Windowing for WindowingTime {
-    fn register_result(&self, was_successful: bool) -> (u64, u64) {
+    fn register_result(&self, was_successful: bool, was_slow: bool) -> Measurements {
         let slots = &mut *self.slots.lock().unwrap();
-        let secs_since_epoch = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
-        let idx = (secs_since_epoch % slots.len() as u64) as usize;
-        let (last_since_epoch, success_count, failure_count) = slots[idx];
-        if last_since_epoch < secs_since_epoch {
-            if was_successful {
-                slots[idx] = (secs_since_epoch, 1, 0);
-            } else {
-                slots[idx] = (secs_since_epoch, 0, 1);
-            }
+        let buckets_since_epoch = buckets_since_epoch(self.clock.as_ref(), self.bucket);
+        let idx = (buckets_since_epoch % slots.len() as u64) as usize;
+        let (last_since_epoch, mut success_count, mut failure_count, mut slow_count) = slots[idx];
+        if last_since_epoch < buckets_since_epoch {
+            (success_count, failure_count, slow_count) = (0, 0, 0);
+        }
+        if was_successful {
+            success_count += 1;
         } else {
-            if was_successful {
-                slots[idx] = (secs_since_epoch, success_count + 1, failure_count);
-            } else {
-                slots[idx] = (secs_since_epoch, success_count, failure_count + 1);
-            }
+            failure_count += 1;
         }
+        slots[idx] = (buckets_since_epoch, success_count, failure_count, slow_count + was_slow as u64);
+        Self::sum(slots, buckets_since_epoch)
+    }
 
-        slots.iter().map(|&(last_since_epoch, success_count, failure_count)| {
-            if (secs_since_epoch - last_since_epoch) as usize <= slots.len() {
-                (success_count, failure_count)
-            } else {
-                (0, 0)
-            }
-        }).fold((0, 0), |(sc1, fc1), (sc2, fc2)| (sc1 + sc2, fc1 + fc2))
+    fn measurements(&self) -> Measurements {
+        let slots = &*self.slots.lock().unwrap();
+        Self::sum(slots, buckets_since_epoch(self.clock.as_ref(), self.bucket))
     }
 
     fn reset(&self) {
         let slots = &mut *self.slots.lock().unwrap();
-        slots.fill((0, 0, 0));
+        slots.fill((0, 0, 0, 0));
     }
 }
 
 impl Clone for WindowingTime {
     fn clone(&self) -> Self {
         WindowingTime {
-            slots: Arc::clone(&self.slots)
+            slots: Arc::clone(&self.slots),
+            bucket: self.bucket,
+            clock: Arc::clone(&self.clock)
         }
     }
 }
 
+// The trial calls permitted in the half-open state and their results
+#[derive(Default)]
+struct HalfOpenTrials {
+    started: usize,
+    measurements: Measurements
+}
+
 struct Data<W: Windowing> {
     state: Arc<AtomicCell<State>>,
     windowing: Arc<W>,
-    open_state_duration: Arc<Duration>,
-    threshold: f32
+    trials: Arc<Mutex<HalfOpenTrials>>,
+    subscribers: Arc<RwLock<Vec<Subscriber>>>,
+    // the number of trial calls in the half-open state, the other calls are rejected
+    permitted_half_open_calls: usize,
+    open_state_duration: Duration,
+    // grows the open state duration with every failed half-open trial, until the circuit closes
+    backoff: Option<ExponentialBackoff>,
+    // the half-open trials failed in a row and the duration of the current open state
+    reopened: Arc<AtomicU32>,
+    open_duration: Arc<AtomicCell<Duration>>,
+    threshold: f32,
+    // the rates are evaluated once the window holds at least minimum_calls results
+    // until it is set, the empty slots of a count-based window are successful calls
+    minimum_calls: Option<u64>,
+    // calls taking longer are slow, Duration::MAX disables the slow call detection
+    slow_call_duration: Duration,
+    slow_call_threshold: f32,
+    // which errors are failures, all by default
+    record_error: Option<Predicate>,
+    // which errors are not recorded at all
+    ignore_error: Option<Predicate>,
+    // which successful results are failures
+    record_result: Option<Predicate>,
+    clock: Arc<dyn Clock>
 }
 
-impl Data<WindowingCount> {
-    fn new(measurements: usize, threshold: f32, open_state_duration: Duration) -> Self {
+impl <W: Windowing> Data<W> {
+    fn new(windowing: W, threshold: f32, open_state_duration: Duration, clock: Arc<dyn Clock>) -> Self {
         Data {
             state: Arc::new(AtomicCell::new(State::Closed)),
-            windowing: Arc::new(WindowingCount::new(measurements)),
-            open_state_duration: Arc::new(open_state_duration),
-            threshold
-        }
-    }
-}
-
-impl Data<WindowingTime> {
-    fn new(seconds: usize, threshold: f32, open_state_duration: Duration) -> Self {
-        Data {
-            state: Arc::new(AtomicCell::new(State::Closed)),
-            windowing: Arc::new(WindowingTime::new(seconds)),
-            open_state_duration: Arc::new(open_state_duration),
-            threshold
+            windowing: Arc::new(windowing),
+            trials: Arc::new(Mutex::new(HalfOpenTrials::default())),
+            subscribers: Arc::new(RwLock::new(Vec::new())),
+            permitted_half_open_calls: 1,
+            open_state_duration,
+            backoff: None,
+            reopened: Arc::new(AtomicU32::new(0)),
+            open_duration: Arc::new(AtomicCell::new(open_state_duration)),
+            threshold,
+            minimum_calls: None,
+            slow_call_duration: Duration::MAX,
+            slow_call_threshold: 1.0,
+            record_error: None,
+            ignore_error: None,
+            record_result: None,
+            clock
         }
     }
 }
@@ -151,8 +308,21 @@ impl <W: Windowing> Clone for Data<W> {
         Data {
             state: Arc::clone(&self.state),
             windowing: Arc::clone(&self.windowing),
-            open_state_duration: Arc::clone(&self.open_state_duration),
-            threshold: self.threshold.clone()
+            trials: Arc::clone(&self.trials),
+            subscribers: Arc::clone(&self.subscribers),
+            permitted_half_open_calls: self.permitted_half_open_calls,
+            open_state_duration: self.open_state_duration,
+            backoff: self.backoff,
+            reopened: Arc::clone(&self.reopened),
+            open_duration: Arc::clone(&self.open_duration),
+            threshold: self.threshold,
+            minimum_calls: self.minimum_calls,
+            slow_call_duration: self.slow_call_duration,
+            slow_call_threshold: self.slow_call_threshold,
+            record_error: self.record_error.clone(),
+            ignore_error: self.ignore_error.clone(),
+            record_result: self.record_result.clone(),
+            clock: Arc::clone(&self.clock)
         }
     }
 }
@@ -171,52 +341,289 @@ impl <W: Windowing> Clone for CircuitBreaker<W> {
 
 impl CircuitBreaker<WindowingCount> {
     fn new(measurements: usize, threshold: f32, open_state_duration: Duration) -> Self {
+        Self::with_clock(measurements, threshold, open_state_duration, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(measurements: usize, threshold: f32, open_state_duration: Duration, clock: Arc<dyn Clock>) -> Self {
         CircuitBreaker {
-            data: Data::<WindowingCount>::new(measurements, threshold, open_state_duration)
+            data: Data::new(WindowingCount::new(measurements), threshold, open_state_duration, clock)
         }
     }
 }
 
 impl CircuitBreaker<WindowingTime> {
     fn new(seconds: usize, threshold: f32, open_state_duration: Duration) -> Self {
+        Self::with_clock(seconds, threshold, open_state_duration, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(seconds: usize, threshold: f32, open_state_duration: Duration, clock: Arc<dyn Clock>) -> Self {
         CircuitBreaker {
-            data: Data::<WindowingTime>::new(seconds, threshold, open_state_duration)
+            data: Data::new(WindowingTime::new(seconds, Arc::clone(&clock)), threshold, open_state_duration, clock)
         }
     }
 }
 
 impl <W: Windowing> CircuitBreaker<W> {
-    pub fn execute<F, R, E>(&self, f: F) -> Result<R, Error<E>> where F: FnOnce() -> Result<R, E> {
+    pub fn with_windowing(windowing: W, threshold: f32, open_state_duration: Duration, clock: Arc<dyn Clock>) -> Self {
+        CircuitBreaker {
+            data: Data::new(windowing, threshold, open_state_duration, clock)
+        }
+    }
+
+    // the failure rate is not evaluated until the window holds at least minimum_calls results
+    pub fn minimum_calls(mut self, minimum_calls: u64) -> Self {
+        self.data.minimum_calls = Some(minimum_calls.max(1));
+        self
+    }
+
+    // calls taking longer than slow_call_duration are slow,
+    // the circuit opens when the slow call rate reaches slow_call_threshold even if the calls succeed
+    pub fn slow_calls(mut self, slow_call_duration: Duration, slow_call_threshold: f32) -> Self {
+        self.data.slow_call_duration = slow_call_duration;
+        self.data.slow_call_threshold = slow_call_threshold;
+        self
+    }
+
+    // the circuit closes or opens again after the results of all permitted trial calls in the half-open state
+    pub fn half_open_calls(mut self, permitted_calls: usize) -> Self {
+        self.data.permitted_half_open_calls = permitted_calls.max(1);
+        self
+    }
+
+    // only the errors matching the predicate are failures, the other errors are recorded as successes
+    // the predicates apply to the calls with the error type they are configured for
+    pub fn record_error<E: 'static, P>(mut self, record_error: P) -> Self where P: Fn(&E) -> bool + Send + Sync + 'static {
+        self.data.record_error = Some(predicate(record_error));
+        self
+    }
+
+    // the errors matching the predicate are neither failures nor successes, they are not recorded
+    pub fn ignore_error<E: 'static, P>(mut self, ignore_error: P) -> Self where P: Fn(&E) -> bool + Send + Sync + 'static {
+        self.data.ignore_error = Some(predicate(ignore_error));
+        self
+    }
+
+    // the successful results matching the predicate are recorded as failures
+    pub fn record_result<R: 'static, P>(mut self, record_result: P) -> Self where P: Fn(&R) -> bool + Send + Sync + 'static {
+        self.data.record_result = Some(predicate(record_result));
+        self
+    }
+
+    // the predicates apply to the errors of the call, timeouts are always failures
+    fn classify<R: 'static, E: 'static>(&self, result: &Result<R, Error<E>>) -> CallOutcome {
+        let matches = |predicate: &Option<Predicate>, value: &dyn Any| predicate.as_ref().and_then(|predicate| predicate(value));
+        match result {
+            Ok(value) if matches(&self.data.record_result, value) == Some(true) => CallOutcome::Failure,
+            Ok(_) => CallOutcome::Success,
+            Err(Error::Custom(error)) if matches(&self.data.ignore_error, error) == Some(true) => CallOutcome::Ignored,
+            Err(Error::Custom(error)) if matches(&self.data.record_error, error) == Some(false) => CallOutcome::Success,
+            Err(_) => CallOutcome::Failure
+        }
+    }
+
+    // the open state lasts backoff.delay(n) after the n-th failed half-open trial in a row instead of open_state_duration
+    pub fn open_state_backoff(mut self, backoff: ExponentialBackoff) -> Self {
+        self.data.backoff = Some(backoff);
+        self
+    }
+
+    // the open state to move to
+    fn open_state(&self) -> State {
+        State::Open(self.data.clock.now())
+    }
+
+    // sets how long the open state lasts, called only by the thread whose transition to it has succeeded
+    // reopened if a half-open trial has failed
+    fn opened(&self, reopened: bool) {
+        let attempt = if reopened {
+            self.data.reopened.fetch_add(1, Ordering::AcqRel) + 1
+        } else {
+            self.data.reopened.store(0, Ordering::Release);
+            0
+        };
+        let open_duration = match self.data.backoff {
+            Some(backoff) if attempt > 0 => backoff.delay(attempt),
+            _ => self.data.open_state_duration
+        };
+        self.data.open_duration.store(open_duration);
+    }
+
+    // the measurements the rates are evaluated on
+    fn evaluated(&self, measurements: Measurements) -> Measurements {
+        match (self.data.minimum_calls, self.data.windowing.capacity()) {
+            (None, Some(capacity)) => Measurements { calls: capacity.max(measurements.calls), ..measurements },
+            _ => measurements
+        }
+    }
+
+    fn should_open(&self, measurements: &Measurements) -> bool {
+        measurements.calls >= self.data.minimum_calls.unwrap_or(1) && self.exceeds_thresholds(measurements)
+    }
+
+    fn exceeds_thresholds(&self, measurements: &Measurements) -> bool {
+        measurements.failure_rate() >= self.data.threshold || measurements.slow_call_rate() >= self.data.slow_call_threshold
+    }
+
+    pub fn state(&self) -> State {
+        self.data.state.load()
+    }
+
+    // the results in the current window
+    pub fn measurements(&self) -> Measurements {
+        self.data.windowing.measurements()
+    }
+
+    // the subscriber is called on the calling thread, it must not call the circuit breaker
+    pub fn subscribe<S>(&self, subscriber: S) where S: Fn(&Event) + Send + Sync + 'static {
+        self.data.subscribers.write().unwrap().push(Box::new(subscriber));
+    }
+
+    pub fn force_open(&self) {
+        self.force(State::ForcedOpen);
+    }
+
+    pub fn force_close(&self) {
+        self.force(State::Closed);
+    }
+
+    pub fn disable(&self) {
+        self.force(State::Disabled);
+    }
+
+    fn force(&self, to: State) {
+        let mut trials = self.data.trials.lock().unwrap();
+        let from = self.data.state.swap(to);
+        *trials = HalfOpenTrials::default();
+        if to == State::Closed {
+            self.data.windowing.reset();
+            self.data.reopened.store(0, Ordering::Release);
+        }
+        drop(trials);
+        if from != to {
+            self.publish(&self.transition_event(from, to, self.data.windowing.measurements().failure_rate()));
+        }
+    }
+
+    fn publish(&self, event: &Event) {
+        self.data.subscribers.read().unwrap().iter().for_each(|subscriber| subscriber(event));
+    }
+
+    fn transition_event(&self, from: State, to: State, failure_rate: f32) -> Event {
+        Event::StateTransition { from, to, time: self.data.clock.system_time(), failure_rate }
+    }
+
+    fn transition(&self, from: State, to: State, failure_rate: f32) -> Option<Event> {
+        self.data.state.compare_exchange(from, to).ok().map(|_| self.transition_event(from, to, failure_rate))
+    }
+
+    fn reject<R, E>(&self) -> Result<R, Error<E>> {
+        self.publish(&Event::Rejected);
+        Err(Error::Rejected)
+    }
+
+    // executes the call, returns the result, how it is recorded and whether the call was slow
+    fn call<F, R: 'static, E: 'static>(&self, f: F) -> (Result<R, Error<E>>, CallOutcome, bool) where F: FnOnce() -> Result<R, Error<E>> {
+        let start = self.data.clock.now();
+        let result = f();
+        let elapsed = self.data.clock.now().saturating_duration_since(start);
+        let slow = elapsed > self.data.slow_call_duration;
+        let outcome = self.classify(&result);
+        match outcome {
+            CallOutcome::Success => self.publish(&Event::Success { elapsed, slow }),
+            CallOutcome::Failure => self.publish(&Event::Error { elapsed, slow }),
+            CallOutcome::Ignored => self.publish(&Event::Ignored { elapsed })
+        }
+        (result, outcome, slow)
+    }
+
+    fn start_trial(&self) -> bool {
+        let mut trials = self.data.trials.lock().unwrap();
+        if self.data.state.load() == State::HalfOpen && trials.started < self.data.permitted_half_open_calls {
+            trials.started += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn finish_trial(&self, outcome: CallOutcome, was_slow: bool) {
+        let mut trials = self.data.trials.lock().unwrap();
+        // the state was left while the call was running
+        if self.data.state.load() != State::HalfOpen {
+            return;
+        }
+        // an ignored call gives its turn to another trial call
+        if outcome == CallOutcome::Ignored {
+            trials.started -= 1;
+            return;
+        }
+        let was_successful = outcome == CallOutcome::Success;
+        let measurements = &mut trials.measurements;
+        measurements.calls += 1;
+        measurements.failures += !was_successful as u64;
+        measurements.slow_calls += was_slow as u64;
+        if measurements.calls < self.data.permitted_half_open_calls as u64 {
+            return;
+        }
+
+        let failure_rate = measurements.failure_rate();
+        let event = if self.exceeds_thresholds(measurements) {
+            self.transition(State::HalfOpen, self.open_state(), failure_rate).inspect(|_| self.opened(true))
+        } else {
+            self.data.windowing.reset();
+            self.data.reopened.store(0, Ordering::Release);
+            self.transition(State::HalfOpen, State::Closed, failure_rate)
+        };
+        *trials = HalfOpenTrials::default();
+        drop(trials);
+        event.iter().for_each(|event| self.publish(event));
+    }
+
+    pub fn execute<F, R: 'static, E: 'static>(&self, f: F) -> Result<R, Error<E>> where F: FnOnce() -> Result<R, E> {
+        self.execute_call(|| f().map_err(|e| Error::Custom(e)))
+    }
+
+    // the call runs on its own thread, a call not completed within the time limit is recorded as a failure
+    pub fn execute_with_timeout<F, R: Send + 'static, E: Send + 'static>(&self, time_limiter: &TimeLimiter, f: F) -> Result<R, Error<E>>
+        where F: FnOnce(CancellationToken) -> Result<R, E> + Send + 'static {
+        self.execute_call(|| time_limiter.execute(f))
+    }
+
+    fn execute_call<F, R: 'static, E: 'static>(&self, f: F) -> Result<R, Error<E>> where F: FnOnce() -> Result<R, Error<E>> {
         loop {
             let state = self.data.state.load();
             match state {
                 State::Closed => {
-                    let result = f().map_err(|e| Error::Custom(e));
-                    let (success, failure) = self.data.windowing.register_result(result.is_ok());
-                    let failure_ratio = failure as f32 / (success as f32 + failure as f32);
-                    if failure_ratio >= self.data.threshold {
-                        self.data.state.compare_exchange(State::Closed, State::Open(Instant::now()));
+                    let (result, outcome, was_slow) = self.call(f);
+                    if outcome == CallOutcome::Ignored {
+                        return result;
+                    }
+                    let measurements = self.evaluated(self.data.windowing.register_result(outcome == CallOutcome::Success, was_slow));
+                    if self.should_open(&measurements) {
+                        let event = self.transition(State::Closed, self.open_state(), measurements.failure_rate())
+                            .inspect(|_| self.opened(false));
+                        event.iter().for_each(|event| self.publish(event));
                     }
                     return result;
                 },
                 State::Open(changed) => {
-                    if Instant::now().duration_since(changed) > *self.data.open_state_duration {
-                        self.data.state.compare_exchange(State::Open(changed), State::HalfOpen);
+                    if self.data.clock.now().duration_since(changed) > self.data.open_duration.load() {
+                        let event = self.transition(State::Open(changed), State::HalfOpen, self.data.windowing.measurements().failure_rate());
+                        event.iter().for_each(|event| self.publish(event));
                     } else {
-                        return Err(Error::Rejected);
+                        return self.reject();
                     }
                 },
                 State::HalfOpen => {
-                    let result = f().map_err(|e| Error::Custom(e));
-                    if result.is_ok() {
-                        if self.data.state.compare_exchange(State::HalfOpen, State::Closed).is_ok() {
-                            self.data.windowing.reset();
-                        }
-                    } else {
-                        self.data.state.compare_exchange(State::HalfOpen, State::Open(Instant::now()));
+                    if !self.start_trial() {
+                        return self.reject();
                     }
+                    let (result, outcome, was_slow) = self.call(f);
+                    self.finish_trial(outcome, was_slow);
                     return result;
-                }
+                },
+                State::ForcedOpen => return self.reject(),
+                State::Disabled => return f()
             }
         }
     }
@@ -225,6 +632,8 @@ impl <W: Windowing> CircuitBreaker<W> {
 #[cfg(test)]
 mod tests {
     use std::sync::Barrier;
+    use std::thread;
+    use crate::clock::MockClock;
     use super::*;
 
     #[test]
@@ -415,4 +824,243 @@ mod tests {
         assert_eq!(result.len(), 100);
     }
 
+    #[test]
+    fn test_mock_clock_reject_and_recover() {
+        let clock = MockClock::new();
+        let cb = CircuitBreaker::<WindowingCount>::with_clock(10, 0.6, Duration::from_secs(60), Arc::new(clock.clone()));
+
+        for _ in 0..6 {
+            cb.execute(|| { if 1 > 0 { Err("oops") } else { Ok(1) } });
+        }
+        clock.advance(Duration::from_secs(60));
+        // still open until the open state duration has passed
+        assert_eq!(cb.execute(|| { if 1 > 0 { Ok(1) } else { Err("oops") } }), Err(Error::Rejected));
+
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(cb.execute(|| { if 1 > 0 { Ok(1) } else { Err("oops") } }), Ok(1));
+        assert_eq!(cb.execute(|| { if 1 > 0 { Err("oops") } else { Ok(1) } }), Err(Error::Custom("oops")));
+    }
+
+    #[test]
+    fn test_mock_clock_WindowingTime_expiry() {
+        let clock = MockClock::new();
+        let cb = CircuitBreaker::<WindowingTime>::with_clock(2, 0.6, Duration::from_secs(60), Arc::new(clock.clone()));
+
+        for _ in 0..5 {
+            cb.execute(|| { if 1 > 0 { Ok(1) } else { Err("oops") } });
+            cb.execute(|| { if 1 > 0 { Err("oops") } else { Ok(1) } });
+        }
+        // the results of the old seconds are no longer counted
+        clock.advance(Duration::from_secs(10));
+        cb.execute(|| { if 1 > 0 { Ok(1) } else { Err("oops") } });
+        cb.execute(|| { if 1 > 0 { Err("oops") } else { Ok(1) } });
+        assert_eq!(cb.execute(|| { if 1 > 0 { Ok(1) } else { Err("oops") } }), Ok(1));
+        cb.execute(|| { if 1 > 0 { Err("oops") } else { Ok(1) } });
+        cb.execute(|| { if 1 > 0 { Err("oops") } else { Ok(1) } });
+        assert_eq!(cb.execute(|| { if 1 > 0 { Ok(1) } else { Err("oops") } }), Err(Error::Rejected));
+    }
+
+    #[test]
+    fn test_minimum_calls() {
+        let cb = CircuitBreaker::<WindowingTime>::new(10, 0.6, Duration::from_secs(60)).minimum_calls(5);
+
+        // the failure rate is 100% but there are not enough calls to evaluate it
+        for _ in 0..4 {
+            assert_eq!(cb.execute(|| { if 1 > 0 { Err("oops") } else { Ok(1) } }), Err(Error::Custom("oops")));
+        }
+        cb.execute(|| { if 1 > 0 { Err("oops") } else { Ok(1) } });
+        assert_eq!(cb.execute(|| { if 1 > 0 { Ok(1) } else { Err("oops") } }), Err(Error::Rejected));
+    }
+
+    #[test]
+    fn test_count_window_without_minimum_calls() {
+        let clock = MockClock::new();
+        let cb = CircuitBreaker::<WindowingCount>::with_clock(10, 0.6, Duration::from_secs(60), Arc::new(clock.clone()));
+
+        // the empty slots are successful calls, 6 out of 10 calls have to fail
+        cb.execute(|| { if 1 > 0 { Err("oops") } else { Ok(1) } });
+        assert_eq!(cb.state(), State::Closed);
+        for _ in 0..4 {
+            cb.execute(|| { if 1 > 0 { Err("oops") } else { Ok(1) } });
+        }
+        assert_eq!(cb.state(), State::Closed);
+        cb.execute(|| { if 1 > 0 { Err("oops") } else { Ok(1) } });
+        assert_eq!(cb.state(), State::Open(clock.now()));
+
+        // the reset window is empty again
+        cb.force_close();
+        cb.execute(|| { if 1 > 0 { Err("oops") } else { Ok(1) } });
+        assert_eq!(cb.state(), State::Closed);
+    }
+
+    #[test]
+    fn test_slow_calls() {
+        let clock = MockClock::new();
+        let cb = CircuitBreaker::<WindowingCount>::with_clock(10, 0.6, Duration::from_secs(60), Arc::new(clock.clone()))
+            .minimum_calls(4)
+            .slow_calls(Duration::from_secs(1), 0.5);
+        let slow_call = || {
+            clock.advance(Duration::from_secs(2));
+            if 1 > 0 { Ok(1) } else { Err("oops") }
+        };
+
+        assert_eq!(cb.execute(|| { if 1 > 0 { Ok(1) } else { Err("oops") } }), Ok(1));
+        assert_eq!(cb.execute(slow_call), Ok(1));
+        assert_eq!(cb.execute(|| { if 1 > 0 { Ok(1) } else { Err("oops") } }), Ok(1));
+        // 2 out of 4 calls are slow, the dependency has not failed yet
+        assert_eq!(cb.execute(slow_call), Ok(1));
+        assert_eq!(cb.execute(|| { if 1 > 0 { Ok(1) } else { Err("oops") } }), Err(Error::Rejected));
+    }
+
+    #[test]
+    fn test_half_open_calls() {
+        let clock = MockClock::new();
+        let cb = CircuitBreaker::<WindowingCount>::with_clock(10, 0.5, Duration::from_secs(60), Arc::new(clock.clone()))
+            .minimum_calls(1)
+            .half_open_calls(3);
+        cb.execute(|| { if 1 > 0 { Err("oops") } else { Ok(1) } });
+        clock.advance(Duration::from_secs(61));
+
+        // the trial calls overlap, the calls above the permitted 3 are rejected
+        let result = cb.execute(|| {
+            assert_eq!(cb.execute(|| { if 1 > 0 { Ok(1) } else { Err("oops") } }), Ok(1));
+            assert_eq!(cb.execute(|| { if 1 > 0 { Err("oops") } else { Ok(1) } }), Err(Error::Custom("oops")));
+            assert_eq!(cb.execute(|| { if 1 > 0 { Ok(1) } else { Err("oops") } }), Err(Error::Rejected));
+            if 1 > 0 { Err("oops") } else { Ok(1) }
+        });
+        assert_eq!(result, Err(Error::Custom("oops")));
+        // 2 out of 3 trial calls failed
+        assert_eq!(cb.execute(|| { if 1 > 0 { Ok(1) } else { Err("oops") } }), Err(Error::Rejected));
+
+        clock.advance(Duration::from_secs(61));
+        cb.execute(|| { if 1 > 0 { Ok(1) } else { Err("oops") } });
+        cb.execute(|| { if 1 > 0 { Err("oops") } else { Ok(1) } });
+        // the circuit is still half-open after 2 trial calls
+        assert_eq!(cb.data.state.load() == State::HalfOpen, true);
+        cb.execute(|| { if 1 > 0 { Ok(1) } else { Err("oops") } });
+        assert_eq!(cb.data.state.load() == State::Closed, true);
+    }
+
+    #[test]
+    fn test_events() {
+        let clock = MockClock::new();
+        let cb = CircuitBreaker::<WindowingCount>::with_clock(4, 0.5, Duration::from_secs(60), Arc::new(clock.clone()))
+            .minimum_calls(2);
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let events2 = Arc::clone(&events);
+        cb.subscribe(move |event| events2.lock().unwrap().push(event.clone()));
+
+        cb.execute(|| { if 1 > 0 { Ok(1) } else { Err("oops") } });
+        cb.execute(|| { if 1 > 0 { Err("oops") } else { Ok(1) } });
+        let opened = cb.state();
+        assert_eq!(opened, State::Open(clock.now()));
+        cb.execute(|| { if 1 > 0 { Ok(1) } else { Err("oops") } });
+
+        assert_eq!(*events.lock().unwrap(), vec![
+            Event::Success { elapsed: Duration::ZERO, slow: false },
+            Event::Error { elapsed: Duration::ZERO, slow: false },
+            Event::StateTransition { from: State::Closed, to: opened, time: clock.system_time(), failure_rate: 0.5 },
+            Event::Rejected
+        ]);
+        assert_eq!(cb.measurements(), Measurements { calls: 2, failures: 1, slow_calls: 0 });
+    }
+
+    #[test]
+    fn test_manual_state() {
+        let cb = CircuitBreaker::<WindowingCount>::new(10, 0.5, Duration::from_secs(60));
+
+        cb.force_open();
+        assert_eq!(cb.state(), State::ForcedOpen);
+        assert_eq!(cb.execute(|| { if 1 > 0 { Ok(1) } else { Err("oops") } }), Err(Error::Rejected));
+
+        // failures are not recorded while disabled
+        cb.disable();
+        for _ in 0..10 {
+            assert_eq!(cb.execute(|| { if 1 > 0 { Err("oops") } else { Ok(1) } }), Err(Error::Custom("oops")));
+        }
+        assert_eq!(cb.state(), State::Disabled);
+        assert_eq!(cb.measurements(), Measurements::default());
+
+        cb.execute(|| { if 1 > 0 { Ok(1) } else { Err("oops") } });
+        cb.force_close();
+        cb.execute(|| { if 1 > 0 { Ok(1) } else { Err("oops") } });
+        assert_eq!(cb.state(), State::Closed);
+        assert_eq!(cb.measurements().calls, 1);
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum RepositoryError {
+        NotFound,
+        InvalidQuery,
+        Unavailable
+    }
+
+    #[test]
+    fn test_error_classification() {
+        let cb = CircuitBreaker::<WindowingCount>::new(10, 0.5, Duration::from_secs(60))
+            .minimum_calls(1)
+            .ignore_error(|error: &RepositoryError| *error == RepositoryError::NotFound)
+            .record_error(|error: &RepositoryError| *error != RepositoryError::InvalidQuery)
+            .record_result(|status: &i32| *status == 503);
+
+        assert_eq!(cb.execute(|| { if 1 > 0 { Err(RepositoryError::NotFound) } else { Ok(200) } }), Err(Error::Custom(RepositoryError::NotFound)));
+        assert_eq!(cb.measurements(), Measurements::default());
+
+        cb.execute(|| { if 1 > 0 { Err(RepositoryError::InvalidQuery) } else { Ok(200) } });
+        cb.execute(|| { if 1 > 0 { Ok(200) } else { Err(RepositoryError::Unavailable) } });
+        assert_eq!(cb.measurements(), Measurements { calls: 2, failures: 0, slow_calls: 0 });
+
+        assert_eq!(cb.execute(|| { if 1 > 0 { Ok(503) } else { Err(RepositoryError::Unavailable) } }), Ok(503));
+        cb.execute(|| { if 1 > 0 { Err(RepositoryError::Unavailable) } else { Ok(200) } });
+        assert_eq!(matches!(cb.state(), State::Open(_)), true);
+    }
+
+    #[test]
+    fn test_open_state_backoff() {
+        let clock = MockClock::new();
+        let cb = CircuitBreaker::<WindowingCount>::with_clock(10, 0.5, Duration::from_secs(10), Arc::new(clock.clone()))
+            .minimum_calls(1)
+            .open_state_backoff(ExponentialBackoff::new(Duration::from_secs(20), 2.0, Duration::from_secs(30)));
+        let fail = || cb.execute(|| { if 1 > 0 { Err("oops") } else { Ok(1) } });
+
+        assert_eq!(fail(), Err(Error::Custom("oops")));
+        clock.advance(Duration::from_secs(11));
+        // the first trial fails, the circuit is open for 20 seconds
+        assert_eq!(fail(), Err(Error::Custom("oops")));
+        clock.advance(Duration::from_secs(15));
+        assert_eq!(fail(), Err(Error::Rejected));
+        clock.advance(Duration::from_secs(6));
+        // the second trial fails, the circuit is open for 40 seconds capped at 30
+        assert_eq!(fail(), Err(Error::Custom("oops")));
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(fail(), Err(Error::Rejected));
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(cb.execute(|| { if 1 > 0 { Ok(1) } else { Err("oops") } }), Ok(1));
+
+        // closing the circuit resets the backoff
+        assert_eq!(fail(), Err(Error::Custom("oops")));
+        clock.advance(Duration::from_secs(11));
+        assert_eq!(fail(), Err(Error::Custom("oops")));
+    }
+
+    #[test]
+    fn test_execute_with_timeout() {
+        let cb = CircuitBreaker::<WindowingCount>::new(10, 0.5, Duration::from_secs(60))
+            .minimum_calls(2)
+            .ignore_error(|error: &&str| *error == "not found");
+        let time_limiter = TimeLimiter::new(Duration::from_millis(20));
+
+        assert_eq!(cb.execute_with_timeout(&time_limiter, |_| { if 1 > 0 { Err("not found") } else { Ok(1) } }), Err(Error::Custom("not found")));
+        assert_eq!(cb.execute_with_timeout(&time_limiter, |_| { if 1 > 0 { Ok(1) } else { Err("oops") } }), Ok(1));
+        let result = cb.execute_with_timeout(&time_limiter, |token| {
+            while !token.is_cancelled() {
+                thread::sleep(Duration::from_millis(1));
+            }
+            if 1 > 0 { Ok(1) } else { Err("oops") }
+        });
+        assert_eq!(result, Err(Error::Timeout));
+        assert_eq!(cb.measurements(), Measurements { calls: 2, failures: 1, slow_calls: 0 });
+        assert_eq!(matches!(cb.state(), State::Open(_)), true);
+    }
+
 }
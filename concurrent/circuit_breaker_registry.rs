@@ -0,0 +1,287 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use crate::backoff::ExponentialBackoff;
+use crate::circuit_breaker::{CircuitBreaker, Windowing, WindowingCount, WindowingTime};
+use crate::clock::{Clock, SystemClock};
+
+pub type DynCircuitBreaker = CircuitBreaker<Box<dyn Windowing + Send + Sync>>;
+
+const DEFAULT_CONFIG: &str = "default";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigError {
+    InvalidWindowSize,
+    InvalidFailureRateThreshold(f32),
+    InvalidSlowCallThreshold(f32),
+    InvalidMinimumCalls,
+    InvalidHalfOpenCalls,
+    UnknownConfig(String)
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Window {
+    // the last N calls
+    Count(usize),
+    // the calls of the last N seconds
+    Time(usize)
+}
+
+// A validated circuit breaker configuration, the defaults match CircuitBreaker
+#[derive(Debug, Clone, PartialEq)]
+pub struct CircuitBreakerConfig {
+    window: Window,
+    failure_rate_threshold: f32,
+    open_state_duration: Duration,
+    backoff: Option<ExponentialBackoff>,
+    // None evaluates the rates of a count window from its first call, with the empty slots as successes
+    minimum_calls: Option<u64>,
+    // None disables the slow call detection
+    slow_calls: Option<(Duration, f32)>,
+    permitted_half_open_calls: usize
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        CircuitBreakerConfig {
+            window: Window::Count(100),
+            failure_rate_threshold: 0.5,
+            open_state_duration: Duration::from_secs(60),
+            backoff: None,
+            minimum_calls: None,
+            slow_calls: None,
+            permitted_half_open_calls: 1
+        }
+    }
+}
+
+impl CircuitBreakerConfig {
+    pub fn builder() -> CircuitBreakerConfigBuilder {
+        CircuitBreakerConfig::default().to_builder()
+    }
+
+    // a builder starting from this configuration, to override some of its values
+    pub fn to_builder(&self) -> CircuitBreakerConfigBuilder {
+        CircuitBreakerConfigBuilder {
+            config: self.clone()
+        }
+    }
+
+    pub fn circuit_breaker(&self, clock: Arc<dyn Clock>) -> DynCircuitBreaker {
+        let windowing: Box<dyn Windowing + Send + Sync> = match self.window {
+            Window::Count(measurements) => Box::new(WindowingCount::new(measurements)),
+            Window::Time(seconds) => Box::new(WindowingTime::new(seconds, Arc::clone(&clock)))
+        };
+        let mut circuit_breaker = CircuitBreaker::with_windowing(windowing, self.failure_rate_threshold, self.open_state_duration, clock)
+            .half_open_calls(self.permitted_half_open_calls);
+        if let Some(minimum_calls) = self.minimum_calls {
+            circuit_breaker = circuit_breaker.minimum_calls(minimum_calls);
+        }
+        if let Some(backoff) = self.backoff {
+            circuit_breaker = circuit_breaker.open_state_backoff(backoff);
+        }
+        if let Some((slow_call_duration, slow_call_threshold)) = self.slow_calls {
+            circuit_breaker = circuit_breaker.slow_calls(slow_call_duration, slow_call_threshold);
+        }
+        circuit_breaker
+    }
+}
+
+pub struct CircuitBreakerConfigBuilder {
+    config: CircuitBreakerConfig
+}
+
+impl CircuitBreakerConfigBuilder {
+    pub fn window(mut self, window: Window) -> Self {
+        self.config.window = window;
+        self
+    }
+
+    pub fn failure_rate_threshold(mut self, failure_rate_threshold: f32) -> Self {
+        self.config.failure_rate_threshold = failure_rate_threshold;
+        self
+    }
+
+    pub fn open_state_duration(mut self, open_state_duration: Duration) -> Self {
+        self.config.open_state_duration = open_state_duration;
+        self
+    }
+
+    pub fn open_state_backoff(mut self, backoff: ExponentialBackoff) -> Self {
+        self.config.backoff = Some(backoff);
+        self
+    }
+
+    pub fn minimum_calls(mut self, minimum_calls: u64) -> Self {
+        self.config.minimum_calls = Some(minimum_calls);
+        self
+    }
+
+    pub fn slow_calls(mut self, slow_call_duration: Duration, slow_call_threshold: f32) -> Self {
+        self.config.slow_calls = Some((slow_call_duration, slow_call_threshold));
+        self
+    }
+
+    pub fn half_open_calls(mut self, permitted_calls: usize) -> Self {
+        self.config.permitted_half_open_calls = permitted_calls;
+        self
+    }
+
+    pub fn build(self) -> Result<CircuitBreakerConfig, ConfigError> {
+        let config = self.config;
+        let is_rate = |rate: f32| rate > 0.0 && rate <= 1.0;
+        if matches!(config.window, Window::Count(0) | Window::Time(0)) {
+            return Err(ConfigError::InvalidWindowSize);
+        }
+        if !is_rate(config.failure_rate_threshold) {
+            return Err(ConfigError::InvalidFailureRateThreshold(config.failure_rate_threshold));
+        }
+        if let Some((_, slow_call_threshold)) = config.slow_calls.filter(|&(_, threshold)| !is_rate(threshold)) {
+            return Err(ConfigError::InvalidSlowCallThreshold(slow_call_threshold));
+        }
+        if config.minimum_calls == Some(0) {
+            return Err(ConfigError::InvalidMinimumCalls);
+        }
+        if config.permitted_half_open_calls == 0 {
+            return Err(ConfigError::InvalidHalfOpenCalls);
+        }
+        Ok(config)
+    }
+}
+
+/*
+   Named circuit breakers created from named configurations.
+   A circuit breaker is created on the first request of its name and shared by all later requests,
+   the configuration is used only when it is created.
+   Breakers without a configuration name use the default one.
+ */
+pub struct CircuitBreakerRegistry {
+    configs: Arc<RwLock<HashMap<String, CircuitBreakerConfig>>>,
+    circuit_breakers: Arc<RwLock<BTreeMap<String, DynCircuitBreaker>>>,
+    clock: Arc<dyn Clock>
+}
+
+impl Clone for CircuitBreakerRegistry {
+    fn clone(&self) -> Self {
+        CircuitBreakerRegistry {
+            configs: Arc::clone(&self.configs),
+            circuit_breakers: Arc::clone(&self.circuit_breakers),
+            clock: Arc::clone(&self.clock)
+        }
+    }
+}
+
+impl Default for CircuitBreakerRegistry {
+    fn default() -> Self {
+        Self::new(CircuitBreakerConfig::default())
+    }
+}
+
+impl CircuitBreakerRegistry {
+    pub fn new(default_config: CircuitBreakerConfig) -> Self {
+        Self::with_clock(default_config, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(default_config: CircuitBreakerConfig, clock: Arc<dyn Clock>) -> Self {
+        CircuitBreakerRegistry {
+            configs: Arc::new(RwLock::new(HashMap::from([(DEFAULT_CONFIG.to_string(), default_config)]))),
+            circuit_breakers: Arc::new(RwLock::new(BTreeMap::new())),
+            clock
+        }
+    }
+
+    // adds or replaces the configuration, existing circuit breakers keep their configuration
+    pub fn add_config(&self, name: &str, config: CircuitBreakerConfig) {
+        self.configs.write().unwrap().insert(name.to_string(), config);
+    }
+
+    pub fn config(&self, name: &str) -> Option<CircuitBreakerConfig> {
+        self.configs.read().unwrap().get(name).cloned()
+    }
+
+    pub fn default_config(&self) -> CircuitBreakerConfig {
+        self.config(DEFAULT_CONFIG).unwrap()
+    }
+
+    pub fn circuit_breaker(&self, name: &str) -> DynCircuitBreaker {
+        self.circuit_breaker_with(name, &self.default_config())
+    }
+
+    pub fn circuit_breaker_with_config(&self, name: &str, config_name: &str) -> Result<DynCircuitBreaker, ConfigError> {
+        let config = self.config(config_name).ok_or_else(|| ConfigError::UnknownConfig(config_name.to_string()))?;
+        Ok(self.circuit_breaker_with(name, &config))
+    }
+
+    pub fn circuit_breaker_with(&self, name: &str, config: &CircuitBreakerConfig) -> DynCircuitBreaker {
+        if let Some(circuit_breaker) = self.circuit_breakers.read().unwrap().get(name) {
+            return circuit_breaker.clone();
+        }
+        self.circuit_breakers.write().unwrap()
+            .entry(name.to_string())
+            .or_insert_with(|| config.circuit_breaker(Arc::clone(&self.clock)))
+            .clone()
+    }
+
+    pub fn remove(&self, name: &str) -> Option<DynCircuitBreaker> {
+        self.circuit_breakers.write().unwrap().remove(name)
+    }
+
+    // all circuit breakers ordered by name
+    pub fn iter(&self) -> impl Iterator<Item = (String, DynCircuitBreaker)> {
+        let circuit_breakers: Vec<(String, DynCircuitBreaker)> = self.circuit_breakers.read().unwrap().iter()
+            .map(|(name, circuit_breaker)| (name.clone(), circuit_breaker.clone()))
+            .collect();
+        circuit_breakers.into_iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.circuit_breakers.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::circuit_breaker::{Error, State};
+    use crate::clock::MockClock;
+    use super::*;
+
+    #[test]
+    fn test_config_validation() {
+        assert_eq!(CircuitBreakerConfig::builder().build(), Ok(CircuitBreakerConfig::default()));
+        assert_eq!(CircuitBreakerConfig::builder().window(Window::Time(0)).build(), Err(ConfigError::InvalidWindowSize));
+        assert_eq!(CircuitBreakerConfig::builder().failure_rate_threshold(1.5).build(), Err(ConfigError::InvalidFailureRateThreshold(1.5)));
+        assert_eq!(CircuitBreakerConfig::builder().slow_calls(Duration::from_secs(1), 0.0).build(), Err(ConfigError::InvalidSlowCallThreshold(0.0)));
+        assert_eq!(CircuitBreakerConfig::builder().minimum_calls(0).build(), Err(ConfigError::InvalidMinimumCalls));
+        assert_eq!(CircuitBreakerConfig::builder().half_open_calls(0).build(), Err(ConfigError::InvalidHalfOpenCalls));
+    }
+
+    #[test]
+    fn test_registry() {
+        let clock = MockClock::new();
+        let default_config = CircuitBreakerConfig::builder().window(Window::Count(10)).minimum_calls(5).build().unwrap();
+        let registry = CircuitBreakerRegistry::with_clock(default_config.clone(), Arc::new(clock.clone()));
+        // the same configuration with a lower minimum calls
+        registry.add_config("critical", default_config.to_builder().minimum_calls(1).build().unwrap());
+
+        let payments = registry.circuit_breaker_with_config("payments", "critical").unwrap();
+        let users = registry.circuit_breaker("users");
+        assert_eq!(registry.circuit_breaker_with_config("orders", "unknown").err(), Some(ConfigError::UnknownConfig("unknown".to_string())));
+
+        for circuit_breaker in [&payments, &users] {
+            assert_eq!(circuit_breaker.execute(|| { if 1 > 0 { Err("oops") } else { Ok(1) } }), Err(Error::Custom("oops")));
+        }
+        // the registry returns the existing instance
+        assert_eq!(registry.circuit_breaker("payments").state(), State::Open(clock.now()));
+
+        let health: Vec<(String, State)> = registry.iter().map(|(name, circuit_breaker)| (name, circuit_breaker.state())).collect();
+        assert_eq!(health, vec![("payments".to_string(), State::Open(clock.now())), ("users".to_string(), State::Closed)]);
+
+        assert!(registry.remove("users").is_some());
+        assert_eq!(registry.len(), 1);
+    }
+
+}
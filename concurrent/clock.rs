@@ -0,0 +1,126 @@
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/*
+   A source of time for the rate limiters, the circuit breaker and the delayed queue.
+   SystemClock reads the real time, MockClock is moved manually, so timing can be tested without sleeping.
+ */
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    fn system_time(&self) -> SystemTime;
+
+    fn sleep(&self, duration: Duration);
+
+    // the real time to block on a condvar for a timeout measured by this clock
+    // the caller has to check the time again after waking up
+    fn block_timeout(&self, timeout: Duration) -> Duration;
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+
+    fn block_timeout(&self, timeout: Duration) -> Duration {
+        timeout
+    }
+}
+
+/*
+   A clock which moves only when advanced.
+   Sleeping or blocking with a timeout does not wait, it advances the clock by the duration instead,
+   so a single thread is never stuck waiting for the time to pass.
+   Clones share the same time.
+ */
+pub struct MockClock {
+    start: Instant,
+    start_system_time: SystemTime,
+    elapsed: Arc<Mutex<Duration>>
+}
+
+impl Clone for MockClock {
+    fn clone(&self) -> Self {
+        MockClock {
+            start: self.start,
+            start_system_time: self.start_system_time,
+            elapsed: Arc::clone(&self.elapsed)
+        }
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        MockClock {
+            start: Instant::now(),
+            start_system_time: SystemTime::now(),
+            elapsed: Arc::new(Mutex::new(Duration::ZERO))
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap() += duration;
+    }
+
+    // time advanced since the clock was created
+    pub fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        self.start_system_time + self.elapsed()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+
+    fn block_timeout(&self, timeout: Duration) -> Duration {
+        self.advance(timeout);
+        Duration::ZERO
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mock_clock() {
+        let clock = MockClock::new();
+        let (now, system_time) = (clock.now(), clock.system_time());
+
+        clock.advance(Duration::from_secs(10));
+        clock.clone().sleep(Duration::from_secs(5));
+        assert_eq!(clock.block_timeout(Duration::from_secs(1)), Duration::ZERO);
+
+        assert_eq!(clock.now() - now, Duration::from_secs(16));
+        assert_eq!(clock.system_time().duration_since(system_time).unwrap(), Duration::from_secs(16));
+        assert_eq!(clock.elapsed(), Duration::from_secs(16));
+    }
+
+}
@@ -0,0 +1,232 @@
+use std::hash::Hash;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use crate::clock::Clock;
+use crate::keyed_rate_limiter::KeyedRateLimiter;
+use crate::rate_limiting::{Acquisition, RateLimiter};
+
+/*
+   A rate limiter combining several limiters, e.g. 100/s AND 10k/min AND 1M/day.
+   A request is granted only if all limiters grant it, otherwise none of the permits are consumed:
+   permits taken from the earlier limiters are revoked when a later one denies the request, and the metrics
+   of the earlier limiters do not count the withdrawn grant.
+   Limiters are checked in the order they were added, so the most specific (and most often exhausted) should go first.
+   The limiters are not acquired atomically, concurrent requests might be denied by permits which are revoked right after.
+   The implementation is thread-safe.
+ */
+pub struct CompositeRateLimiter {
+    limiters: Arc<Vec<Box<dyn RateLimiter + Send + Sync>>>
+}
+
+impl Clone for CompositeRateLimiter {
+    fn clone(&self) -> Self {
+        CompositeRateLimiter {
+            limiters: Arc::clone(&self.limiters)
+        }
+    }
+}
+
+impl CompositeRateLimiter {
+    pub fn new(limiters: Vec<Box<dyn RateLimiter + Send + Sync>>) -> Self {
+        assert!(!limiters.is_empty());
+
+        CompositeRateLimiter {
+            limiters: Arc::new(limiters)
+        }
+    }
+
+    pub fn acquire(&self) -> bool {
+        self.try_acquire(1).is_granted()
+    }
+}
+
+impl RateLimiter for CompositeRateLimiter {
+    fn try_acquire(&self, permits: usize) -> Acquisition {
+        let acquired_at = self.clock().now();
+        for (idx, limiter) in self.limiters.iter().enumerate() {
+            let acquisition = limiter.try_acquire(permits);
+            if !acquisition.is_granted() {
+                // all or nothing
+                self.limiters[..idx].iter().rev().for_each(|limiter| limiter.revoke(permits, acquired_at));
+                return acquisition;
+            }
+        }
+        Acquisition::Granted
+    }
+
+    fn release(&self, permits: usize, acquired_at: Instant) {
+        self.limiters.iter().for_each(|limiter| limiter.release(permits, acquired_at));
+    }
+
+    fn revoke(&self, permits: usize, acquired_at: Instant) {
+        self.limiters.iter().for_each(|limiter| limiter.revoke(permits, acquired_at));
+    }
+
+    // the limiters are expected to share the clock
+    fn clock(&self) -> &dyn Clock {
+        self.limiters[0].clock()
+    }
+}
+
+// a level of the hierarchy, resolving the limiter a request is charged to
+trait Level<R>: Send + Sync {
+    fn limiter(&self, request: &R) -> Box<dyn RateLimiter + Send + Sync>;
+}
+
+struct KeyedLevel<R, K, L> {
+    key: Box<dyn Fn(&R) -> K + Send + Sync>,
+    limiters: KeyedRateLimiter<K, L>
+}
+
+impl <R, K, L> Level<R> for KeyedLevel<R, K, L> where K: Hash + Eq + Clone + Send, L: RateLimiter + Clone + Send + Sync + 'static {
+    fn limiter(&self, request: &R) -> Box<dyn RateLimiter + Send + Sync> {
+        Box::new(self.limiters.limiter(&(self.key)(request)))
+    }
+}
+
+struct GlobalLevel<L> {
+    limiter: L
+}
+
+impl <R, L> Level<R> for GlobalLevel<L> where L: RateLimiter + Clone + Send + Sync + 'static {
+    fn limiter(&self, _request: &R) -> Box<dyn RateLimiter + Send + Sync> {
+        Box::new(self.limiter.clone())
+    }
+}
+
+/*
+   A hierarchy of rate limits, e.g. user under tenant under global.
+   Each level maps a request to its own limiter (the user's, the tenant's, the global one),
+   a request is granted only if all levels grant it and otherwise none of the permits are consumed.
+   Levels are checked from the first added, so the hierarchy should be built from the most specific level up.
+   The implementation is thread-safe.
+ */
+pub struct HierarchicalRateLimiter<R> {
+    levels: Arc<Vec<Box<dyn Level<R>>>>
+}
+
+impl <R> Clone for HierarchicalRateLimiter<R> {
+    fn clone(&self) -> Self {
+        HierarchicalRateLimiter {
+            levels: Arc::clone(&self.levels)
+        }
+    }
+}
+
+impl <R> Default for HierarchicalRateLimiter<R> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl <R> HierarchicalRateLimiter<R> {
+    pub fn new() -> Self {
+        HierarchicalRateLimiter {
+            levels: Arc::new(Vec::new())
+        }
+    }
+
+    // a level with a limiter per key extracted from the request, e.g. per user or per tenant
+    pub fn keyed_level<K, L, F>(self, key: F, limiters: KeyedRateLimiter<K, L>) -> Self
+        where F: Fn(&R) -> K + Send + Sync + 'static, K: Hash + Eq + Clone + Send + 'static, L: RateLimiter + Clone + Send + Sync + 'static, R: 'static {
+        self.level(Box::new(KeyedLevel { key: Box::new(key), limiters }))
+    }
+
+    // a level with a single limiter shared by all requests
+    pub fn global_level<L>(self, limiter: L) -> Self where L: RateLimiter + Clone + Send + Sync + 'static {
+        self.level(Box::new(GlobalLevel { limiter }))
+    }
+
+    pub fn try_acquire(&self, request: &R, permits: usize) -> Acquisition {
+        self.limiter(request).try_acquire(permits)
+    }
+
+    // permits are not held while waiting, all levels are retried together
+    pub fn acquire_timeout(&self, request: &R, permits: usize, timeout: Duration) -> Acquisition {
+        self.limiter(request).acquire_timeout(permits, timeout)
+    }
+
+    pub fn release(&self, request: &R, permits: usize, acquired_at: Instant) {
+        self.limiter(request).release(permits, acquired_at);
+    }
+
+    // the limiters of all levels for the request, combined into one
+    pub fn limiter(&self, request: &R) -> CompositeRateLimiter {
+        assert!(!self.levels.is_empty());
+
+        CompositeRateLimiter::new(self.levels.iter().map(|level| level.limiter(request)).collect())
+    }
+
+    fn level(mut self, level: Box<dyn Level<R>>) -> Self {
+        Arc::get_mut(&mut self.levels).expect("levels are added before the limiter is shared").push(level);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::thread::JoinHandle;
+    use crate::rate_limiting::FixedWindow;
+    use super::*;
+
+    #[test]
+    fn test_composite_all_or_nothing() {
+        let per_second = FixedWindow::new(5, Duration::from_secs(1));
+        let per_minute = FixedWindow::new(8, Duration::from_secs(60));
+        let limiter = CompositeRateLimiter::new(vec![Box::new(per_second.clone()), Box::new(per_minute.clone())]);
+
+        let acquired_at = Instant::now();
+        assert!(limiter.try_acquire(4).is_granted());
+        assert!(limiter.try_acquire(1).is_granted());
+        // rejected by the first limiter
+        assert!(!limiter.acquire());
+        per_second.release(5, acquired_at);
+        // rejected by the second limiter, the permits of the first one are given back
+        assert!(!limiter.try_acquire(4).is_granted());
+        let snapshot = per_second.snapshot();
+        assert_eq!((snapshot.granted, snapshot.rejected_full), (2, 1));
+        assert!(per_second.try_acquire(5).is_granted());
+        assert!(per_minute.try_acquire(3).is_granted());
+    }
+
+    #[test]
+    fn test_hierarchy() {
+        let limiter = HierarchicalRateLimiter::new()
+            .keyed_level(|request: &(&str, &str)| request.0.to_string(), KeyedRateLimiter::new(4, Duration::from_secs(60), || FixedWindow::new(3, Duration::from_secs(60))))
+            .keyed_level(|request: &(&str, &str)| request.1.to_string(), KeyedRateLimiter::new(4, Duration::from_secs(60), || FixedWindow::new(5, Duration::from_secs(60))))
+            .global_level(FixedWindow::new(7, Duration::from_secs(60)));
+
+        // user limit
+        assert!(limiter.try_acquire(&("alice", "acme"), 3).is_granted());
+        assert!(!limiter.try_acquire(&("alice", "acme"), 1).is_granted());
+        // tenant limit, the denied request does not burn bob's permits
+        assert!(!limiter.try_acquire(&("bob", "acme"), 3).is_granted());
+        assert!(limiter.try_acquire(&("bob", "acme"), 2).is_granted());
+        assert!(!limiter.try_acquire(&("carol", "acme"), 1).is_granted());
+        // global limit
+        assert!(!limiter.try_acquire(&("dave", "initech"), 3).is_granted());
+        assert!(limiter.try_acquire(&("dave", "initech"), 2).is_granted());
+        assert!(!limiter.try_acquire(&("erin", "initech"), 1).is_granted());
+    }
+
+    #[test]
+    fn test_multiple_threads() {
+        let limiter = HierarchicalRateLimiter::new()
+            .keyed_level(|user: &usize| *user, KeyedRateLimiter::new(8, Duration::from_secs(60), || FixedWindow::new(100, Duration::from_secs(60))))
+            .global_level(FixedWindow::new(500, Duration::from_secs(60)));
+
+        let tasks: Vec<JoinHandle<usize>> = (0..8).map(|idx| (idx, limiter.clone()))
+            .map(|(idx, limiter)| {
+                thread::spawn(move || {
+                    (0..200).filter(|_| limiter.try_acquire(&idx, 1).is_granted()).count()
+                })
+            }).collect();
+
+        // each user is limited by its own window, all users together by the global one
+        let granted: Vec<usize> = tasks.into_iter().map(|thread| thread.join().unwrap()).collect();
+        assert!(granted.iter().all(|&count| count <= 100));
+        assert_eq!(granted.iter().sum::<usize>(), 500);
+    }
+
+}
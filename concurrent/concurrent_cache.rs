@@ -1,49 +1,223 @@
+use std::any::Any;
 use std::collections::HashMap;
+use std::convert::Infallible;
 use std::hash::Hash;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::time::Instant;
+use data_structures::cache::Cache;
+use data_structures::cache_stats::{CacheStats, EvictionCause};
 
+enum LoadState<V> {
+    Loading,
+    Loaded(V),
+    // the loader error, type-erased as the error type is chosen per call
+    Failed(Arc<dyn Any + Send + Sync>),
+    // the loader panicked, waiters have to retry
+    Abandoned
+}
+
+// A load of a missing key shared by all callers requesting the key at the same time.
+struct InFlight<V> {
+    state: Mutex<LoadState<V>>,
+    condvar: Condvar
+}
+
+impl<V> InFlight<V> {
+    fn new() -> Self {
+        InFlight { state: Mutex::new(LoadState::Loading), condvar: Condvar::new() }
+    }
+
+    fn complete(&self, state: LoadState<V>) {
+        *self.state.lock().unwrap() = state;
+        self.condvar.notify_all();
+    }
+}
+
+// Completes the load as abandoned if the loader panics, so waiters do not block forever.
+struct LoadGuard<'a, K: Hash + Eq, V> {
+    in_flight_loads: &'a Mutex<HashMap<K, Arc<InFlight<V>>>>,
+    key: &'a K,
+    in_flight: Arc<InFlight<V>>,
+    is_completed: bool
+}
+
+impl<'a, K: Hash + Eq, V> LoadGuard<'a, K, V> {
+    fn complete(mut self, state: LoadState<V>) {
+        self.in_flight_loads.lock().unwrap().remove(self.key);
+        self.in_flight.complete(state);
+        self.is_completed = true;
+    }
+}
+
+impl<'a, K: Hash + Eq, V> Drop for LoadGuard<'a, K, V> {
+    fn drop(&mut self) {
+        if !self.is_completed {
+            self.in_flight_loads.lock().unwrap().remove(self.key);
+            self.in_flight.complete(LoadState::Abandoned);
+        }
+    }
+}
+
+/*
+   A thread-safe cache with single-flight loading.
+   Concurrent callers missing the same key wait for a single load, while loads of other keys proceed.
+   Loader errors are propagated to all waiting callers and are not cached.
+ */
 struct ConcurrentCache<K, V> {
-    map: Arc<RwLock<HashMap<K, V>>>
+    map: Arc<RwLock<HashMap<K, V>>>,
+    in_flight_loads: Arc<Mutex<HashMap<K, Arc<InFlight<V>>>>>,
+    stats: Arc<CacheStats>
 }
 
 impl <K, V> Clone for ConcurrentCache<K, V> {
     fn clone(&self) -> Self {
-        ConcurrentCache { map: Arc::clone(&self.map) }
+        ConcurrentCache {
+            map: Arc::clone(&self.map),
+            in_flight_loads: Arc::clone(&self.in_flight_loads),
+            stats: Arc::clone(&self.stats)
+        }
     }
 }
 
-impl<K, V> ConcurrentCache<K, V> where K: Hash + Eq + Copy, V: Copy {
+impl<K, V> ConcurrentCache<K, V> where K: Hash + Eq + Clone, V: Clone {
     fn new() -> ConcurrentCache<K, V> {
-        ConcurrentCache { map: Arc::new(RwLock::new(HashMap::new())) }
+        ConcurrentCache {
+            map: Arc::new(RwLock::new(HashMap::new())),
+            in_flight_loads: Arc::new(Mutex::new(HashMap::new())),
+            stats: Arc::new(CacheStats::new())
+        }
     }
 
     fn getOrLoad<F>(&self, key: K,  f: F) -> V where F: FnOnce(K) -> V {
-        if let Some(value) = self.get(key) {
-            return value;
+        match self.try_get_or_load(key, |key| Ok::<V, Infallible>(f(key))) {
+            Ok(value) => value,
+            Err(never) => match never {}
+        }
+    }
+
+    fn try_get_or_load<F, E>(&self, key: K, f: F) -> Result<V, E>
+        where F: FnOnce(K) -> Result<V, E>, E: Clone + Send + Sync + 'static {
+        if let Some(value) = self.get_by_ref(&key) {
+            return Ok(value);
+        }
+        loop {
+            let (in_flight, is_leader) = {
+                let mut in_flight_guard = self.in_flight_loads.lock().unwrap();
+                // the value could have been loaded since the lookup
+                if let Some(value) = self.map.read().unwrap().get(&key) {
+                    return Ok(value.clone());
+                }
+                match in_flight_guard.get(&key) {
+                    Some(in_flight) => (Arc::clone(in_flight), false),
+                    None => {
+                        let in_flight = Arc::new(InFlight::new());
+                        in_flight_guard.insert(key.clone(), Arc::clone(&in_flight));
+                        (in_flight, true)
+                    }
+                }
+            };
+
+            if is_leader {
+                let guard = LoadGuard { in_flight_loads: &self.in_flight_loads, key: &key, in_flight, is_completed: false };
+                let start = Instant::now();
+                let result = f(key.clone());
+                self.stats.record_load(start.elapsed());
+                match &result {
+                    Ok(value) => {
+                        // publish the value before the load stops being tracked
+                        self.put(key.clone(), value.clone());
+                        guard.complete(LoadState::Loaded(value.clone()));
+                    },
+                    Err(error) => guard.complete(LoadState::Failed(Arc::new(error.clone())))
+                }
+                return result;
+            }
+
+            let mut state = in_flight.state.lock().unwrap();
+            while let LoadState::Loading = *state {
+                state = in_flight.condvar.wait(state).unwrap();
+            }
+            match &*state {
+                LoadState::Loaded(value) => return Ok(value.clone()),
+                LoadState::Failed(error) => {
+                    // the leader might have used a loader with another error type, then load again
+                    if let Some(error) = error.downcast_ref::<E>() {
+                        return Err(error.clone());
+                    }
+                },
+                _ => {}
+            }
         }
-        let value = f(key);
-        self.put(key, value);
-        value
     }
 
     fn put(&self, key: K,  value: V) {
         let mut map_guard = self.map.write().unwrap();
-        map_guard.insert(key, value);
+        if map_guard.insert(key, value).is_some() {
+            self.stats.record_eviction(EvictionCause::Replaced);
+        }
     }
 
     fn get(&self, key: K) -> Option<V> {
-        let mut map_guard = self.map.read().unwrap();
-        map_guard.get(&key).map(|&value| value)
+        self.get_by_ref(&key)
+    }
+
+    fn get_by_ref(&self, key: &K) -> Option<V> {
+        let map_guard = self.map.read().unwrap();
+        let value = map_guard.get(key).cloned();
+        if value.is_some() {
+            self.stats.record_hit();
+        } else {
+            self.stats.record_miss();
+        }
+        value
     }
 
     fn len(&self) -> usize {
-        let mut map_guard = self.map.write().unwrap();
+        let map_guard = self.map.write().unwrap();
         map_guard.len()
     }
 }
 
+impl<K, V> Cache<K, V> for ConcurrentCache<K, V> where K: Hash + Eq + Clone, V: Clone {
+    fn get(&mut self, key: &K) -> Option<V> {
+        self.get_by_ref(key)
+    }
+
+    fn put(&mut self, key: K, value: V) {
+        ConcurrentCache::put(self, key, value);
+    }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        let mut map_guard = self.map.write().unwrap();
+        let value = map_guard.remove(key);
+        if value.is_some() {
+            self.stats.record_eviction(EvictionCause::Explicit);
+        }
+        value
+    }
+
+    fn len(&self) -> usize {
+        ConcurrentCache::len(self)
+    }
+
+    fn clear(&mut self) {
+        let mut map_guard = self.map.write().unwrap();
+        self.stats.record_evictions(EvictionCause::Explicit, map_guard.len() as u64);
+        map_guard.clear();
+    }
+
+    fn stats(&self) -> &CacheStats {
+        &self.stats
+    }
+
+    fn get_or_insert_with<F>(&mut self, key: K, f: F) -> V where F: FnOnce(&K) -> V {
+        self.getOrLoad(key, |key| f(&key))
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use std::thread;
     use std::thread::JoinHandle;
     use std::time::{Duration, Instant};
@@ -111,6 +285,130 @@ mod tests {
         assert_eq!(cache.len(), 4 * 100);
     }
 
+    #[test]
+    fn test_load_stats() {
+        let cache = ConcurrentCache::new();
+        let tasks: Vec<JoinHandle<()>> = (0..4).map(|idx| (idx, cache.clone()))
+            .map(|(idx, cache)| {
+                thread::spawn(move || {
+                    for i in 0..100 {
+                        cache.getOrLoad(i, |x| {
+                            thread::sleep(Duration::from_millis(1));
+                            100
+                        });
+                    }
+                })
+            }).collect();
+
+        tasks.into_iter().for_each(|thread| {
+            thread.join();
+        });
+        let stats = cache.stats().snapshot();
+        // every key is loaded once
+        assert_eq!(stats.requests(), 4 * 100);
+        assert_eq!(stats.loads, 100);
+        assert!(stats.average_load_time() >= Duration::from_millis(1));
+    }
+
+    // blocks the loading leader until the given number of callers wait for its load
+    fn wait_for_waiters<K: Hash + Eq, V>(cache: &ConcurrentCache<K, V>, key: &K, waiters: usize) {
+        // the in-flight load is shared by the map, the leader and every waiting caller
+        while cache.in_flight_loads.lock().unwrap().get(key).map_or(0, Arc::strong_count) < waiters + 2 {
+            thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn test_single_flight_load() {
+        let cache = ConcurrentCache::new();
+        let loads = Arc::new(AtomicUsize::new(0));
+        let tasks: Vec<JoinHandle<i32>> = (0..8).map(|_| (cache.clone(), loads.clone()))
+            .map(|(cache, loads)| {
+                thread::spawn(move || {
+                    cache.getOrLoad(1, |x| {
+                        loads.fetch_add(1, Ordering::SeqCst);
+                        wait_for_waiters(&cache, &1, 7);
+                        x * 100
+                    })
+                })
+            }).collect();
+
+        let values: Vec<i32> = tasks.into_iter().map(|thread| thread.join().unwrap()).collect();
+        assert_eq!(values, vec![100; 8]);
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_single_flight_load_error() {
+        let cache: ConcurrentCache<i32, i32> = ConcurrentCache::new();
+        let loads = Arc::new(AtomicUsize::new(0));
+        let tasks: Vec<JoinHandle<Result<i32, String>>> = (0..8).map(|_| (cache.clone(), loads.clone()))
+            .map(|(cache, loads)| {
+                thread::spawn(move || {
+                    cache.try_get_or_load(1, |_| {
+                        loads.fetch_add(1, Ordering::SeqCst);
+                        wait_for_waiters(&cache, &1, 7);
+                        Err(String::from("oops"))
+                    })
+                })
+            }).collect();
+
+        tasks.into_iter().for_each(|thread| {
+            assert_eq!(thread.join().unwrap(), Err(String::from("oops")));
+        });
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+        // errors are not cached
+        assert_eq!(cache.try_get_or_load(1, |_| Ok::<i32, String>(100)), Ok(100));
+    }
+
+    #[test]
+    fn test_load_other_keys_not_blocked() {
+        let cache = ConcurrentCache::new();
+        let cache2 = cache.clone();
+        let slow_load = thread::spawn(move || {
+            cache2.getOrLoad(1, |_| {
+                thread::sleep(Duration::from_millis(200));
+                100
+            })
+        });
+        thread::sleep(Duration::from_millis(10));
+
+        let start = Instant::now();
+        assert_eq!(cache.getOrLoad(2, |_| 200), 200);
+        assert!(start.elapsed().as_millis() < 100);
+        assert_eq!(slow_load.join().unwrap(), 100);
+    }
+
+    #[test]
+    fn test_load_panic_releases_waiters() {
+        let cache = ConcurrentCache::new();
+        let cache2 = cache.clone();
+        let panicking_load = thread::spawn(move || {
+            cache2.getOrLoad(1, |_| {
+                thread::sleep(Duration::from_millis(50));
+                panic!("oops")
+            })
+        });
+        thread::sleep(Duration::from_millis(10));
+
+        // waits for the panicking load and then loads the value itself
+        assert_eq!(cache.getOrLoad(1, |_| 100), 100);
+        assert!(panicking_load.join().is_err());
+    }
+
+    #[test]
+    fn test_cache_trait() {
+        let mut cache = ConcurrentCache::new();
+        assert_eq!(cache.get_or_insert_with(String::from("a"), |key| key.len()), 1);
+        assert_eq!(cache.get_or_insert_with(String::from("a"), |_| 0), 1);
+        Cache::put(&mut cache, String::from("bb"), 2);
+        assert_eq!(Cache::get(&mut cache, &String::from("bb")), Some(2));
+        assert_eq!(cache.remove(&String::from("a")), Some(1));
+        cache.clear();
+        assert!(cache.is_empty());
+        assert_eq!(cache.stats().snapshot().evictions(EvictionCause::Explicit), 2);
+    }
+
     #[test]
     fn test_reads_writes() {
         let mut cache = ConcurrentCache::new();
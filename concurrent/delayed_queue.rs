@@ -6,6 +6,7 @@ use std::sync::atomic;
 use std::thread;
 use std::thread::ThreadId;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::clock::{Clock, SystemClock};
 
 static SEQ_GENERATOR: AtomicUsize = AtomicUsize::new(0);
 
@@ -23,9 +24,9 @@ impl <T> ScheduledEntry<T> {
             id: SEQ_GENERATOR.fetch_add(1, atomic::Ordering::Release) as u128
         }
     }
-    fn delay_ms(&self) -> i128 {
+    fn delay_ms(&self, now: SystemTime) -> i128 {
         self.time.duration_since(UNIX_EPOCH).unwrap().as_millis() as i128 -
-            SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as i128
+            now.duration_since(UNIX_EPOCH).unwrap().as_millis() as i128
     }
     pub fn entry(self) -> T {
         self.entry
@@ -69,7 +70,8 @@ struct SharedData<T> {
 pub struct DelayedQueue<T> {
     data: Arc<Mutex<SharedData<T>>>,
     condvar: Arc<Condvar>,
-    is_active: Arc<AtomicBool>
+    is_active: Arc<AtomicBool>,
+    clock: Arc<dyn Clock>
 }
 
 impl <T> Clone for DelayedQueue<T> {
@@ -77,7 +79,8 @@ impl <T> Clone for DelayedQueue<T> {
         DelayedQueue {
             data: Arc::clone(&self.data),
             condvar: Arc::clone(&self.condvar),
-            is_active: Arc::clone(&self.is_active)
+            is_active: Arc::clone(&self.is_active),
+            clock: Arc::clone(&self.clock)
         }
     }
 }
@@ -88,6 +91,9 @@ The leader will only wait for the delay time of the peek element in the queue, w
  */
 impl <T> DelayedQueue<T> {
     pub fn new() -> Self {
+        Self::with_clock(Arc::new(SystemClock))
+    }
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
         DelayedQueue {
             data: Arc::new(Mutex::new(
                 SharedData {
@@ -96,7 +102,8 @@ impl <T> DelayedQueue<T> {
                 }
             )),
             condvar: Arc::new(Condvar::new()),
-            is_active: Arc::new(AtomicBool::new(true))
+            is_active: Arc::new(AtomicBool::new(true)),
+            clock
         }
     }
     pub fn offer(&self, entry: ScheduledEntry<T>) {
@@ -126,7 +133,7 @@ impl <T> DelayedQueue<T> {
                 let first = guard.queue.peek();
                 match first {
                     Some(scheduled_entry) => {
-                        let delay = scheduled_entry.delay_ms();
+                        let delay = scheduled_entry.delay_ms(self.clock.system_time());
                         // if an element is ready to be delivered, return it
                         // waiting is not required
                         if delay <= 0 {
@@ -140,7 +147,7 @@ impl <T> DelayedQueue<T> {
                             // wait for the peek element scheduled delay
                         } else {
                             guard.leader.insert(thread::current().id());
-                            guard = self.condvar.wait_timeout(guard, Duration::from_millis(delay as u64)).unwrap().0;
+                            guard = self.condvar.wait_timeout(guard, self.clock.block_timeout(Duration::from_millis(delay as u64))).unwrap().0;
                             // if the current thread is still the leader, clean up and try to return the peek element
                             if let Some(leader) = guard.leader {
                                 if leader == thread::current().id() {
@@ -182,6 +189,7 @@ mod tests {
     use std::ops::Add;
     use std::thread::JoinHandle;
     use futures::future::Join;
+    use crate::clock::MockClock;
     use super::*;
 
     #[test]
@@ -286,6 +294,21 @@ mod tests {
         assert_eq!(*data.lock().unwrap(), expected);
     }
 
+    #[test]
+    fn test_mock_clock() {
+        let clock = MockClock::new();
+        let queue = DelayedQueue::with_clock(Arc::new(clock.clone()));
+        for i in 1..10 {
+            queue.offer(ScheduledEntry { entry: i, id: i, time: clock.system_time().add(Duration::from_secs(100 - i as u64 * 10)) });
+        }
+
+        // waiting for an entry moves the clock to its scheduled time
+        for i in (1..10).rev() {
+            assert_eq!(queue.get().unwrap().id, i);
+            assert_eq!(clock.elapsed(), Duration::from_secs(100 - i as u64 * 10));
+        }
+    }
+
     #[test]
     #[should_panic(expected = "Queue is not active")]
     fn test_stop() {
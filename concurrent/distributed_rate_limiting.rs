@@ -0,0 +1,791 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use crate::rate_limiting::{Acquisition, RateLimiter};
+
+// Distributed rate limiting algorithms: FixedWindow, SlidingWindow, GCRA with state kept in a shared store
+
+/*
+   A store shared by all replicas of a service, holding the rate limiters state.
+   Every operation has to be atomic across all the store clients.
+ */
+pub trait RateLimitStore {
+    // adds delta to the counter under key, a new counter starts at 0 and expires after ttl; returns the new value
+    fn increment(&self, key: &str, delta: i64, ttl: Duration) -> io::Result<i64>;
+
+    fn get(&self, key: &str) -> io::Result<Option<i64>>;
+
+    // sets the value only if the current one is expected (None if the key is absent); returns whether the value was set
+    fn compare_and_set(&self, key: &str, expected: Option<i64>, value: i64, ttl: Duration) -> io::Result<bool>;
+}
+
+// A store kept in memory, useful for a single process and tests.
+#[derive(Default)]
+pub struct InMemoryStore {
+    // key -> (value, expiry time)
+    data: Mutex<HashMap<String, (i64, Instant)>>
+}
+
+impl InMemoryStore {
+    pub fn new() -> Self {
+        InMemoryStore { data: Mutex::new(HashMap::new()) }
+    }
+}
+
+impl RateLimitStore for InMemoryStore {
+    fn increment(&self, key: &str, delta: i64, ttl: Duration) -> io::Result<i64> {
+        let mut data = self.data.lock().unwrap();
+        let now = Instant::now();
+        let entry = data.entry(key.to_string()).or_insert((0, now + ttl));
+        if entry.1 <= now {
+            *entry = (0, now + ttl);
+        }
+        entry.0 += delta;
+        Ok(entry.0)
+    }
+
+    fn get(&self, key: &str) -> io::Result<Option<i64>> {
+        let data = self.data.lock().unwrap();
+        Ok(data.get(key).filter(|(_, expiry)| *expiry > Instant::now()).map(|&(value, _)| value))
+    }
+
+    fn compare_and_set(&self, key: &str, expected: Option<i64>, value: i64, ttl: Duration) -> io::Result<bool> {
+        let mut data = self.data.lock().unwrap();
+        let now = Instant::now();
+        let current = data.get(key).filter(|(_, expiry)| *expiry > now).map(|&(value, _)| value);
+        if current == expected {
+            data.insert(key.to_string(), (value, now + ttl));
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+}
+
+const MAX_REPLY_LENGTH: i64 = 512 * 1024 * 1024;
+// arrays are read recursively, so their nesting is bounded to keep a malformed reply from overflowing the stack
+const MAX_REPLY_DEPTH: usize = 32;
+
+#[derive(Debug, PartialEq)]
+enum RespValue {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Option<Vec<RespValue>>)
+}
+
+struct RespConnection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream
+}
+
+impl RespConnection {
+    fn connect(addr: SocketAddr, timeout: Duration) -> io::Result<Self> {
+        let stream = TcpStream::connect_timeout(&addr, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        stream.set_nodelay(true)?;
+        Ok(RespConnection { reader: BufReader::new(stream.try_clone()?), writer: stream })
+    }
+
+    // sends all commands at once and reads all their replies
+    fn pipeline(&mut self, commands: &[Vec<String>]) -> io::Result<Vec<RespValue>> {
+        let mut buffer = Vec::new();
+        for command in commands {
+            buffer.extend(format!("*{}\r\n", command.len()).as_bytes());
+            for arg in command {
+                buffer.extend(format!("${}\r\n{}\r\n", arg.len(), arg).as_bytes());
+            }
+        }
+        self.writer.write_all(&buffer)?;
+        let replies = commands.iter().map(|_| self.read_value(0)).collect::<io::Result<Vec<_>>>()?;
+        match replies.iter().find(|reply| matches!(reply, RespValue::Error(_))) {
+            Some(RespValue::Error(message)) => Err(io::Error::other(message.clone())),
+            _ => Ok(replies)
+        }
+    }
+
+    // depth is the number of arrays the value is nested in
+    fn read_value(&mut self, depth: usize) -> io::Result<RespValue> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed"));
+        }
+        let line = line.trim_end_matches("\r\n");
+        if line.is_empty() {
+            return Err(invalid_data("empty reply"));
+        }
+        // the type byte may start a multi-byte character in a malformed reply
+        let (kind, payload) = line.split_at_checked(1).ok_or_else(|| invalid_data(line))?;
+        match kind {
+            "+" => Ok(RespValue::Simple(payload.to_string())),
+            "-" => Ok(RespValue::Error(payload.to_string())),
+            ":" => Ok(RespValue::Integer(parse_integer(payload)?)),
+            "$" => {
+                let len = parse_length(payload)?;
+                if len < 0 {
+                    return Ok(RespValue::Bulk(None));
+                }
+                let mut data = vec![0; len as usize + 2];
+                self.reader.read_exact(&mut data)?;
+                if !data.ends_with(b"\r\n") {
+                    return Err(invalid_data("bulk string without a trailing CRLF"));
+                }
+                data.truncate(len as usize);
+                Ok(RespValue::Bulk(Some(data)))
+            },
+            "*" => {
+                let len = parse_length(payload)?;
+                if len < 0 {
+                    return Ok(RespValue::Array(None));
+                }
+                if depth >= MAX_REPLY_DEPTH {
+                    return Err(invalid_data("array nested too deeply"));
+                }
+                let values = (0..len).map(|_| self.read_value(depth + 1)).collect::<io::Result<Vec<_>>>()?;
+                Ok(RespValue::Array(Some(values)))
+            },
+            _ => Err(invalid_data(line))
+        }
+    }
+}
+
+fn parse_integer(payload: &str) -> io::Result<i64> {
+    payload.parse().map_err(|_| invalid_data(payload))
+}
+
+// the length of a bulk string or an array, -1 is a null reply
+// the length is allocated up front, so it is bounded by the 512MB limit of Redis bulk strings
+fn parse_length(payload: &str) -> io::Result<i64> {
+    match parse_integer(payload)? {
+        len @ -1..=MAX_REPLY_LENGTH => Ok(len),
+        _ => Err(invalid_data(payload))
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn command(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string()).collect()
+}
+
+/*
+   A store client speaking RESP (the Redis protocol) over TCP.
+   A single connection is used, it is re-established lazily after an I/O error.
+ */
+pub struct RespStore {
+    addr: SocketAddr,
+    timeout: Duration,
+    connection: Mutex<Option<RespConnection>>
+}
+
+impl RespStore {
+    pub fn new<A: ToSocketAddrs>(addr: A, timeout: Duration) -> io::Result<Self> {
+        let addr = addr.to_socket_addrs()?.next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address"))?;
+        Ok(RespStore { addr, timeout, connection: Mutex::new(None) })
+    }
+
+    fn execute(&self, commands: &[Vec<String>]) -> io::Result<Vec<RespValue>> {
+        self.with_connection(|connection| connection.pipeline(commands))
+    }
+
+    // runs f holding the connection, so commands of a WATCH transaction are sent over the same connection
+    fn with_connection<T, F>(&self, f: F) -> io::Result<T> where F: FnOnce(&mut RespConnection) -> io::Result<T> {
+        let mut connection = self.connection.lock().unwrap();
+        if connection.is_none() {
+            *connection = Some(RespConnection::connect(self.addr, self.timeout)?);
+        }
+        let result = f(connection.as_mut().unwrap());
+        if result.is_err() {
+            // the connection state is unknown, drop it
+            connection.take();
+        }
+        result
+    }
+}
+
+fn to_integer(value: &RespValue) -> io::Result<Option<i64>> {
+    match value {
+        RespValue::Integer(value) => Ok(Some(*value)),
+        RespValue::Bulk(Some(data)) => {
+            let data = std::str::from_utf8(data).map_err(|_| invalid_data("not a number"))?;
+            Ok(Some(parse_integer(data)?))
+        },
+        RespValue::Bulk(None) => Ok(None),
+        value => Err(invalid_data(&format!("unexpected reply {:?}", value)))
+    }
+}
+
+impl RateLimitStore for RespStore {
+    fn increment(&self, key: &str, delta: i64, ttl: Duration) -> io::Result<i64> {
+        // create the counter with expiry if absent and increment it in one transaction
+        let replies = self.execute(&[
+            command(&["MULTI"]),
+            command(&["SET", key, "0", "PX", &ttl.as_millis().max(1).to_string(), "NX"]),
+            command(&["INCRBY", key, &delta.to_string()]),
+            command(&["EXEC"])
+        ])?;
+        match replies.last() {
+            Some(RespValue::Array(Some(results))) if results.len() == 2 => {
+                to_integer(&results[1])?.ok_or_else(|| invalid_data("missing counter"))
+            },
+            reply => Err(invalid_data(&format!("unexpected reply {:?}", reply)))
+        }
+    }
+
+    fn get(&self, key: &str) -> io::Result<Option<i64>> {
+        let replies = self.execute(&[command(&["GET", key])])?;
+        to_integer(&replies[0])
+    }
+
+    // optimistic locking, the transaction is aborted if another client modifies the key after WATCH
+    fn compare_and_set(&self, key: &str, expected: Option<i64>, value: i64, ttl: Duration) -> io::Result<bool> {
+        self.with_connection(|connection| {
+            let replies = connection.pipeline(&[command(&["WATCH", key]), command(&["GET", key])])?;
+            if to_integer(&replies[1])? != expected {
+                connection.pipeline(&[command(&["UNWATCH"])])?;
+                return Ok(false);
+            }
+            let replies = connection.pipeline(&[
+                command(&["MULTI"]),
+                command(&["SET", key, &value.to_string(), "PX", &ttl.as_millis().max(1).to_string()]),
+                command(&["EXEC"])
+            ])?;
+            Ok(matches!(replies.last(), Some(RespValue::Array(Some(_)))))
+        })
+    }
+}
+
+// milliseconds since EPOCH, the wall clock is shared by all replicas
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+}
+
+// the window of a permit acquired at the given instant, rounded down so it is never later than the window of the grant
+fn acquired_window(acquired_at: Instant, interval: Duration) -> u64 {
+    let elapsed = acquired_at.elapsed().as_nanos().div_ceil(1_000_000) as u64;
+    now_millis().saturating_sub(elapsed) / interval.as_millis() as u64
+}
+
+struct LocalQuota {
+    window: u64,
+    remaining: usize
+}
+
+/*
+   A fixed window algorithm with the window counters kept in a shared store.
+   Windows are aligned to EPOCH, so all replicas use the same window keys.
+   Permits of a denied request are given back to the window.
+   Optionally permits are reserved from the store in batches and handed out locally,
+   which saves round trips at the cost of precision: permits reserved but unused by one replica are not available to others.
+   A store failure grants the permits (fail open), use try_acquire_checked to handle store errors.
+ */
+pub struct DistributedFixedWindow<S> {
+    store: Arc<S>,
+    name: String,
+    max_request_per_interval: usize,
+    interval: Duration,
+    local_batch: usize,
+    local_quota: Arc<Mutex<LocalQuota>>
+}
+
+impl <S> Clone for DistributedFixedWindow<S> {
+    fn clone(&self) -> Self {
+        DistributedFixedWindow {
+            store: Arc::clone(&self.store),
+            name: self.name.clone(),
+            max_request_per_interval: self.max_request_per_interval,
+            interval: self.interval,
+            local_batch: self.local_batch,
+            local_quota: Arc::clone(&self.local_quota)
+        }
+    }
+}
+
+impl <S: RateLimitStore> DistributedFixedWindow<S> {
+    pub fn new(store: Arc<S>, name: &str, max_request_per_interval: usize, interval: Duration) -> Self {
+        assert!(max_request_per_interval > 0 && interval.as_millis() > 0);
+
+        DistributedFixedWindow {
+            store,
+            name: name.to_string(),
+            max_request_per_interval,
+            interval,
+            local_batch: 0,
+            local_quota: Arc::new(Mutex::new(LocalQuota { window: 0, remaining: 0 }))
+        }
+    }
+
+    pub fn with_local_batch(mut self, local_batch: usize) -> Self {
+        self.local_batch = local_batch;
+        self
+    }
+
+    pub fn try_acquire_checked(&self, permits: usize) -> io::Result<Acquisition> {
+        if permits > self.max_request_per_interval {
+            return Ok(Acquisition::Denied { retry_after: Duration::MAX });
+        }
+        let now = now_millis();
+        let interval = self.interval.as_millis() as u64;
+        let window = now / interval;
+        let denied = Acquisition::Denied { retry_after: Duration::from_millis((window + 1) * interval - now) };
+
+        if self.local_batch == 0 {
+            let taken = self.reserve(window, permits)?;
+            if taken == permits {
+                return Ok(Acquisition::Granted);
+            }
+            // all or nothing
+            self.release(window, taken)?;
+            return Ok(denied);
+        }
+
+        let mut local_quota = self.local_quota.lock().unwrap();
+        if local_quota.window != window {
+            *local_quota = LocalQuota { window, remaining: 0 };
+        }
+        if local_quota.remaining < permits {
+            local_quota.remaining += self.reserve(window, self.local_batch.max(permits - local_quota.remaining))?;
+        }
+        if local_quota.remaining >= permits {
+            local_quota.remaining -= permits;
+            Ok(Acquisition::Granted)
+        } else {
+            Ok(denied)
+        }
+    }
+
+    // takes up to permits from the window counter, returns the number of permits taken
+    fn reserve(&self, window: u64, permits: usize) -> io::Result<usize> {
+        let key = format!("{}:{}", self.name, window);
+        let counter = self.store.increment(&key, permits as i64, self.interval * 2)?;
+        let max = self.max_request_per_interval as i64;
+        let taken = (max.min(counter) - max.min(counter - permits as i64)).max(0) as usize;
+        self.release(window, permits - taken)?;
+        Ok(taken)
+    }
+
+    fn release(&self, window: u64, permits: usize) -> io::Result<()> {
+        if permits > 0 {
+            let key = format!("{}:{}", self.name, window);
+            self.store.increment(&key, -(permits as i64), self.interval * 2)?;
+        }
+        Ok(())
+    }
+}
+
+impl <S: RateLimitStore> RateLimiter for DistributedFixedWindow<S> {
+    fn try_acquire(&self, permits: usize) -> Acquisition {
+        self.try_acquire_checked(permits).unwrap_or(Acquisition::Granted)
+    }
+
+    // permits are given back to the window they were acquired in, a store failure loses them until the window ends
+    fn release(&self, permits: usize, acquired_at: Instant) {
+        let window = now_millis() / self.interval.as_millis() as u64;
+        if acquired_window(acquired_at, self.interval) != window {
+            return;
+        }
+        if self.local_batch > 0 {
+            let mut local_quota = self.local_quota.lock().unwrap();
+            if local_quota.window == window {
+                local_quota.remaining += permits;
+            }
+        } else {
+            let _ = DistributedFixedWindow::release(self, window, permits);
+        }
+    }
+}
+
+/*
+   A sliding window algorithm with the window counters kept in a shared store.
+   The counter of the previous window is weighted by the part of it still covered by the sliding window.
+   A store failure grants the permits (fail open), use try_acquire_checked to handle store errors.
+ */
+pub struct DistributedSlidingWindow<S> {
+    store: Arc<S>,
+    name: String,
+    max_request_per_interval: usize,
+    interval: Duration
+}
+
+impl <S> Clone for DistributedSlidingWindow<S> {
+    fn clone(&self) -> Self {
+        DistributedSlidingWindow {
+            store: Arc::clone(&self.store),
+            name: self.name.clone(),
+            max_request_per_interval: self.max_request_per_interval,
+            interval: self.interval
+        }
+    }
+}
+
+impl <S: RateLimitStore> DistributedSlidingWindow<S> {
+    pub fn new(store: Arc<S>, name: &str, max_request_per_interval: usize, interval: Duration) -> Self {
+        assert!(max_request_per_interval > 0 && interval.as_millis() > 0);
+
+        DistributedSlidingWindow { store, name: name.to_string(), max_request_per_interval, interval }
+    }
+
+    pub fn try_acquire_checked(&self, permits: usize) -> io::Result<Acquisition> {
+        if permits > self.max_request_per_interval {
+            return Ok(Acquisition::Denied { retry_after: Duration::MAX });
+        }
+        let now = now_millis();
+        let interval = self.interval.as_millis() as u64;
+        let window = now / interval;
+        let key = format!("{}:{}", self.name, window);
+
+        let prev_counter = self.store.get(&format!("{}:{}", self.name, window - 1))?.unwrap_or(0).max(0) as f64;
+        let counter = self.store.increment(&key, permits as i64, self.interval * 2)?;
+        // counter ratio from the previous window
+        let since_window_start = (now % interval) as f64 / interval as f64;
+        let estimated = prev_counter * (1.0 - since_window_start) + counter as f64;
+        let max = self.max_request_per_interval as f64;
+        if estimated <= max {
+            return Ok(Acquisition::Granted);
+        }
+
+        self.store.increment(&key, -(permits as i64), self.interval * 2)?;
+        let retry_after = if (counter as f64) <= max {
+            // wait until the previous window counter decays enough
+            let ratio = 1.0 - (max - counter as f64) / prev_counter;
+            // the estimate exceeded the limit, so this is positive up to a rounding error
+            self.interval.mul_f64((ratio - since_window_start).max(0.0))
+        } else {
+            Duration::from_millis((window + 1) * interval - now)
+        };
+        Ok(Acquisition::Denied { retry_after })
+    }
+}
+
+impl <S: RateLimitStore> RateLimiter for DistributedSlidingWindow<S> {
+    fn try_acquire(&self, permits: usize) -> Acquisition {
+        self.try_acquire_checked(permits).unwrap_or(Acquisition::Granted)
+    }
+
+    fn release(&self, permits: usize, acquired_at: Instant) {
+        let window = now_millis() / self.interval.as_millis() as u64;
+        if acquired_window(acquired_at, self.interval) != window {
+            return;
+        }
+        let _ = self.store.increment(&format!("{}:{}", self.name, window), -(permits as i64), self.interval * 2);
+    }
+}
+
+/*
+   A generic cell rate algorithm (GCRA) with the theoretical arrival time kept in a shared store.
+   It behaves like a token bucket of burst_size tokens refilled at max_requests_per_interval per refresh_interval.
+   The arrival time is updated with compare-and-set, retried when another replica has changed it.
+   A store failure grants the permits (fail open), use try_acquire_checked to handle store errors.
+ */
+pub struct DistributedGcra<S> {
+    store: Arc<S>,
+    name: String,
+    // microseconds
+    emission_interval: u64,
+    limit: u64,
+    burst_size: usize
+}
+
+impl <S> Clone for DistributedGcra<S> {
+    fn clone(&self) -> Self {
+        DistributedGcra {
+            store: Arc::clone(&self.store),
+            name: self.name.clone(),
+            emission_interval: self.emission_interval,
+            limit: self.limit,
+            burst_size: self.burst_size
+        }
+    }
+}
+
+impl <S: RateLimitStore> DistributedGcra<S> {
+    pub fn new(store: Arc<S>, name: &str, max_requests_per_interval: usize, refresh_interval: Duration, burst_size: usize) -> Self {
+        assert!(max_requests_per_interval > 0 && burst_size > 0);
+
+        let emission_interval = (refresh_interval.as_micros() / max_requests_per_interval as u128) as u64;
+        // the permits are spaced in whole microseconds
+        assert!(emission_interval > 0);
+
+        DistributedGcra {
+            store,
+            name: name.to_string(),
+            emission_interval,
+            limit: emission_interval.saturating_mul(burst_size as u64),
+            burst_size
+        }
+    }
+
+    pub fn try_acquire_checked(&self, permits: usize) -> io::Result<Acquisition> {
+        if permits > self.burst_size {
+            return Ok(Acquisition::Denied { retry_after: Duration::MAX });
+        }
+        loop {
+            let tat = self.store.get(&self.name)?;
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_micros() as u64;
+            let new_tat = (tat.unwrap_or(0) as u64).max(now).saturating_add(self.emission_interval.saturating_mul(permits as u64));
+            let wait = (new_tat - now).saturating_sub(self.limit);
+            if wait > 0 {
+                return Ok(Acquisition::Denied { retry_after: Duration::from_micros(wait) });
+            }
+            // the state is not needed once the bucket is empty again
+            let ttl = Duration::from_micros(new_tat - now);
+            if self.store.compare_and_set(&self.name, tat, new_tat as i64, ttl)? {
+                return Ok(Acquisition::Granted);
+            }
+        }
+    }
+}
+
+impl <S: RateLimitStore> RateLimiter for DistributedGcra<S> {
+    fn try_acquire(&self, permits: usize) -> Acquisition {
+        self.try_acquire_checked(permits).unwrap_or(Acquisition::Granted)
+    }
+
+    fn release(&self, permits: usize, _acquired_at: Instant) {
+        // moves the arrival time back, retried when another replica has changed it
+        while let Ok(Some(tat)) = self.store.get(&self.name) {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_micros() as u64;
+            if tat as u64 <= now {
+                return;
+            }
+            let new_tat = (tat as u64).saturating_sub(self.emission_interval.saturating_mul(permits as u64)).max(now + 1);
+            match self.store.compare_and_set(&self.name, Some(tat), new_tat as i64, Duration::from_micros(new_tat - now)) {
+                Ok(false) => continue,
+                _ => return
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::thread;
+    use super::*;
+
+    // key -> (value, expiry time, version)
+    type ServerData = HashMap<String, (String, Option<Instant>, u64)>;
+
+    // A minimal stand-in for a RESP server supporting the commands used by RespStore.
+    fn start_server() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let data: Arc<Mutex<ServerData>> = Arc::new(Mutex::new(HashMap::new()));
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let data = Arc::clone(&data);
+                thread::spawn(move || serve(stream.unwrap(), data));
+            }
+        });
+        addr
+    }
+
+    fn serve(stream: TcpStream, data: Arc<Mutex<ServerData>>) {
+        stream.set_nodelay(true).unwrap();
+        let mut connection = RespConnection { reader: BufReader::new(stream.try_clone().unwrap()), writer: stream };
+        let mut watched: HashMap<String, u64> = HashMap::new();
+        let mut queued: Option<Vec<Vec<String>>> = None;
+        while let Ok(RespValue::Array(Some(args))) = connection.read_value(0) {
+            let args: Vec<String> = args.into_iter().map(|arg| match arg {
+                RespValue::Bulk(Some(data)) => String::from_utf8(data).unwrap(),
+                _ => panic!("unexpected argument")
+            }).collect();
+            let reply = match (args[0].as_str(), queued.as_mut()) {
+                ("MULTI", _) => {
+                    queued = Some(Vec::new());
+                    "+OK\r\n".to_string()
+                },
+                ("EXEC", Some(_)) => {
+                    let commands = queued.take().unwrap();
+                    let mut data = data.lock().unwrap();
+                    let is_dirty = watched.drain().any(|(key, version)| version_of(&mut data, &key) != version);
+                    if is_dirty {
+                        "*-1\r\n".to_string()
+                    } else {
+                        let replies: Vec<String> = commands.iter().map(|command| apply(&mut data, command)).collect();
+                        format!("*{}\r\n{}", replies.len(), replies.concat())
+                    }
+                },
+                (_, Some(commands)) => {
+                    commands.push(args);
+                    "+QUEUED\r\n".to_string()
+                },
+                ("WATCH", None) => {
+                    let version = version_of(&mut data.lock().unwrap(), &args[1]);
+                    watched.insert(args[1].clone(), version);
+                    "+OK\r\n".to_string()
+                },
+                ("UNWATCH", None) => {
+                    watched.clear();
+                    "+OK\r\n".to_string()
+                },
+                (_, None) => apply(&mut data.lock().unwrap(), &args)
+            };
+            connection.writer.write_all(reply.as_bytes()).unwrap();
+        }
+    }
+
+    // the version of a key changes on every write, 0 if the key is absent
+    fn version_of(data: &mut ServerData, key: &str) -> u64 {
+        match data.get(key) {
+            Some((_, Some(expiry), _)) if *expiry <= Instant::now() => {
+                data.remove(key);
+                0
+            },
+            Some((_, _, version)) => *version,
+            None => 0
+        }
+    }
+
+    fn apply(data: &mut ServerData, args: &[String]) -> String {
+        let exists = version_of(data, &args[1]) > 0;
+        match args[0].as_str() {
+            "GET" => match data.get(&args[1]) {
+                Some((value, _, _)) => format!("${}\r\n{}\r\n", value.len(), value),
+                None => "$-1\r\n".to_string()
+            },
+            "SET" => {
+                let nx = args.iter().any(|arg| arg == "NX");
+                if nx && exists {
+                    return "$-1\r\n".to_string();
+                }
+                let expiry = args.iter().position(|arg| arg == "PX")
+                    .map(|idx| Instant::now() + Duration::from_millis(args[idx + 1].parse().unwrap()));
+                let version = data.get(&args[1]).map_or(0, |entry| entry.2) + 1;
+                data.insert(args[1].clone(), (args[2].clone(), expiry, version));
+                "+OK\r\n".to_string()
+            },
+            "INCRBY" => {
+                let entry = data.entry(args[1].clone()).or_insert((String::from("0"), None, 0));
+                let value = entry.0.parse::<i64>().unwrap() + args[2].parse::<i64>().unwrap();
+                *entry = (value.to_string(), entry.1, entry.2 + 1);
+                format!(":{}\r\n", value)
+            },
+            command => format!("-ERR unknown command {}\r\n", command)
+        }
+    }
+
+    #[test]
+    fn test_in_memory_store() {
+        let store = InMemoryStore::new();
+        assert_eq!(store.increment("a", 2, Duration::from_millis(20)).unwrap(), 2);
+        assert_eq!(store.increment("a", -1, Duration::from_millis(20)).unwrap(), 1);
+        assert!(!store.compare_and_set("a", None, 5, Duration::from_millis(20)).unwrap());
+        assert!(store.compare_and_set("a", Some(1), 5, Duration::from_millis(20)).unwrap());
+        assert_eq!(store.get("a").unwrap(), Some(5));
+        thread::sleep(Duration::from_millis(30));
+        assert_eq!(store.get("a").unwrap(), None);
+        assert_eq!(store.increment("a", 1, Duration::from_millis(20)).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_resp_store() {
+        let store = RespStore::new(start_server(), Duration::from_secs(1)).unwrap();
+        assert_eq!(store.get("a").unwrap(), None);
+        assert_eq!(store.increment("a", 2, Duration::from_millis(100)).unwrap(), 2);
+        assert_eq!(store.increment("a", -1, Duration::from_millis(100)).unwrap(), 1);
+        assert!(!store.compare_and_set("a", None, 5, Duration::from_millis(100)).unwrap());
+        assert!(store.compare_and_set("a", Some(1), 5, Duration::from_millis(100)).unwrap());
+        assert_eq!(store.get("a").unwrap(), Some(5));
+        thread::sleep(Duration::from_millis(120));
+        assert_eq!(store.get("a").unwrap(), None);
+        assert!(store.compare_and_set("a", None, 7, Duration::from_millis(100)).unwrap());
+    }
+
+    #[test]
+    fn test_fixed_window_replicas_share_quota() {
+        let store = Arc::new(RespStore::new(start_server(), Duration::from_secs(1)).unwrap());
+        let replica1 = DistributedFixedWindow::new(Arc::clone(&store), "api", 10, Duration::from_secs(3600));
+        let replica2 = DistributedFixedWindow::new(Arc::clone(&store), "api", 10, Duration::from_secs(3600));
+        assert!(replica1.try_acquire(6).is_granted());
+        // a denied request does not use up the quota
+        assert!(!replica2.try_acquire(5).is_granted());
+        assert!(replica2.try_acquire(4).is_granted());
+        assert!(!replica1.try_acquire(1).is_granted());
+    }
+
+    #[test]
+    fn test_fixed_window_local_batch() {
+        let store = Arc::new(InMemoryStore::new());
+        let replicas: Vec<DistributedFixedWindow<InMemoryStore>> = (0..3)
+            .map(|_| DistributedFixedWindow::new(Arc::clone(&store), "api", 10, Duration::from_secs(3600)).with_local_batch(4))
+            .collect();
+        let granted = (0..10).flat_map(|_| replicas.iter())
+            .filter(|replica| replica.try_acquire(1).is_granted())
+            .count();
+        assert_eq!(granted, 10);
+        assert_eq!(store.get(&format!("api:{}", now_millis() / 3_600_000)).unwrap(), Some(10));
+    }
+
+    #[test]
+    fn test_sliding_window_replicas_share_quota() {
+        let store = Arc::new(InMemoryStore::new());
+        let replica1 = DistributedSlidingWindow::new(Arc::clone(&store), "api", 10, Duration::from_secs(3600));
+        let replica2 = replica1.clone();
+        assert!(replica1.try_acquire(6).is_granted());
+        assert!(!replica2.try_acquire(5).is_granted());
+        assert!(replica2.try_acquire(4).is_granted());
+        assert!(!replica1.try_acquire(1).is_granted());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_gcra_sub_microsecond_interval() {
+        DistributedGcra::new(Arc::new(InMemoryStore::new()), "api", 1000, Duration::from_micros(10), 1);
+    }
+
+    #[test]
+    fn test_gcra_replicas_share_quota() {
+        let store = Arc::new(RespStore::new(start_server(), Duration::from_secs(1)).unwrap());
+        let replica1 = DistributedGcra::new(Arc::clone(&store), "api", 10, Duration::from_millis(100), 3);
+        let replica2 = DistributedGcra::new(Arc::clone(&store), "api", 10, Duration::from_millis(100), 3);
+        assert!(replica1.try_acquire(2).is_granted());
+        assert!(replica2.try_acquire(1).is_granted());
+        match replica1.try_acquire(1) {
+            Acquisition::Denied { retry_after } => assert!(retry_after.as_millis() <= 10),
+            acquisition => panic!("unexpected {:?}", acquisition)
+        }
+        thread::sleep(Duration::from_millis(10));
+        assert!(replica2.try_acquire(1).is_granted());
+    }
+
+    // reads a single reply sent by a server
+    fn read_reply(reply: Vec<u8>) -> io::Result<RespValue> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || listener.accept().unwrap().0.write_all(&reply));
+        RespConnection::connect(addr, Duration::from_secs(1))?.read_value(0)
+    }
+
+    #[test]
+    fn test_malformed_replies() {
+        assert_eq!(read_reply(b"$3\r\nabc\r\n".to_vec()).unwrap(), RespValue::Bulk(Some(b"abc".to_vec())));
+        assert_eq!(read_reply(b"$-1\r\n".to_vec()).unwrap(), RespValue::Bulk(None));
+        let nested = "*1\r\n".repeat(MAX_REPLY_DEPTH) + ":1\r\n";
+        assert!(read_reply(nested.into_bytes()).is_ok());
+        let too_nested = "*1\r\n".repeat(MAX_REPLY_DEPTH + 1) + ":1\r\n";
+        for reply in ["é\r\n", "$-2\r\n", "$9223372036854775807\r\n", "*536870913\r\n", "$3\r\nabcde\r\n", &too_nested] {
+            assert_eq!(read_reply(reply.as_bytes().to_vec()).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn test_store_failure_fails_open() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let store = Arc::new(RespStore::new(addr, Duration::from_millis(100)).unwrap());
+        let limiter = DistributedFixedWindow::new(store, "api", 1, Duration::from_secs(1));
+        assert!(limiter.try_acquire_checked(1).is_err());
+        assert!(limiter.try_acquire(1).is_granted());
+    }
+
+}
@@ -0,0 +1,228 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use crate::clock::{Clock, SystemClock};
+use crate::rate_limiter_metrics::{RateLimiterMetrics, RateLimiterSnapshot};
+use crate::rate_limiting::{Acquisition, RateLimiter};
+
+/*
+   A generic cell rate algorithm (GCRA) to control the traffic rate, a leaky bucket without a mutex.
+   The state is a single theoretical arrival time (TAT) - the time when the bucket is empty again.
+   Each permit moves the TAT by emission_interval = refresh_interval / max_requests_per_interval.
+   A request is allowed if the TAT it would set is at most burst_size emission intervals ahead of now.
+   The decision is made with one compare-and-swap, so callers never block each other.
+ */
+pub struct Gcra {
+    // nanoseconds since start
+    tat: Arc<AtomicU64>,
+    start: Instant,
+    emission_interval: u64,
+    // how far the TAT can be ahead of now
+    limit: u64,
+    burst_size: usize,
+    metrics: Arc<RateLimiterMetrics>,
+    clock: Arc<dyn Clock>
+}
+
+impl Clone for Gcra {
+    fn clone(&self) -> Self {
+        Gcra {
+            tat: Arc::clone(&self.tat),
+            start: self.start,
+            emission_interval: self.emission_interval,
+            limit: self.limit,
+            burst_size: self.burst_size,
+            metrics: Arc::clone(&self.metrics),
+            clock: Arc::clone(&self.clock)
+        }
+    }
+}
+
+impl Gcra {
+    pub fn new(max_requests_per_interval: usize, refresh_interval: Duration, burst_size: usize) -> Self {
+        Self::with_clock(max_requests_per_interval, refresh_interval, burst_size, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(max_requests_per_interval: usize, refresh_interval: Duration, burst_size: usize, clock: Arc<dyn Clock>) -> Self {
+        assert!(max_requests_per_interval > 0 && burst_size > 0);
+
+        let emission_interval = (refresh_interval.as_nanos() / max_requests_per_interval as u128) as u64;
+        // the permits are spaced in whole nanoseconds
+        assert!(emission_interval > 0);
+
+        Gcra {
+            tat: Arc::new(AtomicU64::new(0)),
+            start: clock.now(),
+            emission_interval,
+            limit: emission_interval.saturating_mul(burst_size as u64),
+            burst_size,
+            metrics: Arc::new(RateLimiterMetrics::new()),
+            clock
+        }
+    }
+
+    pub fn acquire(&self) -> bool {
+        self.try_acquire(1).is_granted()
+    }
+
+    // the queue depth is the number of callers sleeping until their reserved slot
+    pub fn snapshot(&self) -> RateLimiterSnapshot {
+        let now = self.now_nanos();
+        let ahead = self.tat.load(Ordering::Acquire).saturating_sub(now).min(self.limit);
+        let available_permits = ((self.limit - ahead) / self.emission_interval) as usize;
+        self.metrics.snapshot(self.metrics.waiting(), available_permits)
+    }
+
+    fn now_nanos(&self) -> u64 {
+        self.clock.now().saturating_duration_since(self.start).as_nanos() as u64
+    }
+
+    // tries to move the TAT for the permits, a request arriving up to max_wait before its slot reserves it
+    fn reserve(&self, permits: usize, max_wait: Duration) -> Result<Duration, Duration> {
+        let max_wait = max_wait.as_nanos().min(u64::MAX as u128) as u64;
+        let increment = self.emission_interval.saturating_mul(permits as u64);
+        let mut tat = self.tat.load(Ordering::Acquire);
+        loop {
+            let now = self.now_nanos();
+            let new_tat = tat.max(now).saturating_add(increment);
+            let wait = (new_tat - now).saturating_sub(self.limit);
+            if wait > max_wait {
+                return Err(Duration::from_nanos(wait));
+            }
+            match self.tat.compare_exchange_weak(tat, new_tat, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => return Ok(Duration::from_nanos(wait)),
+                Err(current) => tat = current
+            }
+        }
+    }
+}
+
+impl RateLimiter for Gcra {
+    fn try_acquire(&self, permits: usize) -> Acquisition {
+        if permits > self.burst_size {
+            return self.metrics.record(Acquisition::Denied { retry_after: Duration::MAX });
+        }
+        let acquisition = match self.reserve(permits, Duration::ZERO) {
+            Ok(_) => Acquisition::Granted,
+            Err(retry_after) => Acquisition::Denied { retry_after }
+        };
+        self.metrics.record(acquisition)
+    }
+
+    fn release(&self, permits: usize, _acquired_at: Instant) {
+        let increment = self.emission_interval.saturating_mul(permits as u64);
+        let _ = self.tat.fetch_update(Ordering::AcqRel, Ordering::Acquire, |tat| Some(tat.saturating_sub(increment)));
+    }
+
+    fn clock(&self) -> &dyn Clock {
+        self.clock.as_ref()
+    }
+
+    fn metrics(&self) -> Option<&RateLimiterMetrics> {
+        Some(self.metrics.as_ref())
+    }
+
+    // the slot is reserved up front, so waiting requests are served in the order they arrived
+    // and requests larger than burst_size can be granted after waiting
+    fn acquire_timeout(&self, permits: usize, timeout: Duration) -> Acquisition {
+        let acquisition = match self.reserve(permits, timeout) {
+            Ok(wait) if wait.is_zero() => Acquisition::Granted,
+            Ok(wait) => {
+                self.metrics.start_waiting();
+                self.clock.sleep(wait);
+                self.metrics.stop_waiting();
+                Acquisition::Queued(wait)
+            },
+            Err(retry_after) if timeout.is_zero() => Acquisition::Denied { retry_after },
+            Err(retry_after) => Acquisition::TimedOut { retry_after }
+        };
+        self.metrics.record(acquisition)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::hint::spin_loop;
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+    use crate::clock::MockClock;
+    use super::*;
+
+    #[test]
+    fn test_gcra_burst() {
+        let gcra = Gcra::new(10, Duration::from_millis(100), 5);
+        (0..5).for_each(|_| assert!(gcra.acquire()));
+        match gcra.try_acquire(1) {
+            Acquisition::Denied { retry_after } => assert!(retry_after.as_millis() > 5 && retry_after.as_millis() <= 10),
+            acquisition => panic!("unexpected {:?}", acquisition)
+        }
+        assert_eq!(gcra.try_acquire(6), Acquisition::Denied { retry_after: Duration::MAX });
+    }
+
+    #[test]
+    fn test_gcra_mock_clock() {
+        let clock = MockClock::new();
+        let gcra = Gcra::with_clock(10, Duration::from_millis(100), 2, Arc::new(clock.clone()));
+        assert!(gcra.try_acquire(2).is_granted());
+        assert_eq!(gcra.try_acquire(1), Acquisition::Denied { retry_after: Duration::from_millis(10) });
+        clock.advance(Duration::from_millis(10));
+        assert!(gcra.acquire());
+        // waiting moves the mock clock to the reserved slot
+        assert_eq!(gcra.acquire_timeout(3, Duration::from_millis(30)), Acquisition::Queued(Duration::from_millis(30)));
+        assert_eq!(clock.elapsed(), Duration::from_millis(40));
+        assert_eq!(gcra.acquire_timeout(1, Duration::from_millis(5)), Acquisition::TimedOut { retry_after: Duration::from_millis(10) });
+
+        let snapshot = gcra.snapshot();
+        assert_eq!((snapshot.granted, snapshot.queued, snapshot.rejected_full, snapshot.rejected_timeout), (3, 1, 1, 1));
+        assert_eq!(snapshot.available_permits, 0);
+        clock.advance(Duration::from_millis(30));
+        assert_eq!(gcra.snapshot().available_permits, 2);
+    }
+
+    #[test]
+    fn test_gcra_timeout() {
+        let gcra = Gcra::new(10, Duration::from_millis(100), 1);
+        assert!(gcra.acquire());
+        assert!(!gcra.acquire_timeout(2, Duration::from_millis(5)).is_granted());
+        let start = Instant::now();
+        // the second permit is available after 10ms, the third one after 20ms
+        assert!(gcra.acquire_timeout(2, Duration::from_millis(30)).is_granted());
+        assert!(start.elapsed().as_millis() >= 15);
+        // the wait for more permits than a u64 of nanoseconds holds saturates instead of overflowing
+        assert!(matches!(gcra.acquire_timeout(usize::MAX, Duration::from_millis(5)), Acquisition::TimedOut { .. }));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_gcra_sub_nanosecond_interval() {
+        Gcra::new(1000, Duration::from_nanos(10), 1);
+    }
+
+    #[test]
+    fn test_gcra() {
+        let gcra = Gcra::new(10, Duration::from_millis(100), 1);
+        let counter = Arc::new(AtomicUsize::new(0));
+
+        (0..4).for_each(|_| {
+            let g = gcra.clone();
+            let c = counter.clone();
+            thread::spawn(move || {
+                while c.load(Ordering::SeqCst) < 50 {
+                    if g.acquire() {
+                        c.fetch_add(1, Ordering::SeqCst);
+                    }
+                }
+            });
+        });
+
+        let start = Instant::now();
+        while counter.load(Ordering::SeqCst) < 50 {
+            spin_loop();
+        }
+
+        // 10 reqs per 100 ms
+        // to get 50 reqs, we have to spend 50/10 = 5, 5 * 100ms = 500ms
+        assert!(start.elapsed().as_millis() >= 450 && start.elapsed().as_millis() <= 550);
+    }
+
+}
@@ -0,0 +1,169 @@
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use crate::clock::{Clock, SystemClock};
+use crate::rate_limiting::{Acquisition, RateLimiter};
+
+struct KeyedEntry<L> {
+    limiter: L,
+    last_access: Instant
+}
+
+struct Shard<K, L> {
+    limiters: HashMap<K, KeyedEntry<L>>,
+    next_sweep: Instant
+}
+
+/*
+   A rate limiter keeping a separate limiter per key (tenant, IP, API key).
+   Limiters are created lazily by the factory on the first access of a key.
+   Keys are spread over shards, each with its own lock, so different keys rarely contend.
+   Limiters not accessed for idle_timeout are evicted, a shard is swept at most once per idle_timeout.
+   The idle_timeout should be longer than the limiter's window, otherwise an evicted key gets a fresh quota.
+   The implementation is thread-safe.
+ */
+pub struct KeyedRateLimiter<K, L> {
+    shards: Arc<Vec<Mutex<Shard<K, L>>>>,
+    factory: Arc<dyn Fn() -> L + Send + Sync>,
+    idle_timeout: Duration,
+    clock: Arc<dyn Clock>
+}
+
+impl <K, L> Clone for KeyedRateLimiter<K, L> {
+    fn clone(&self) -> Self {
+        KeyedRateLimiter {
+            shards: Arc::clone(&self.shards),
+            factory: Arc::clone(&self.factory),
+            idle_timeout: self.idle_timeout,
+            clock: Arc::clone(&self.clock)
+        }
+    }
+}
+
+impl <K, L> KeyedRateLimiter<K, L> where K: Hash + Eq + Clone, L: RateLimiter + Clone {
+    pub fn new<F>(shards: usize, idle_timeout: Duration, factory: F) -> Self where F: Fn() -> L + Send + Sync + 'static {
+        Self::with_clock(shards, idle_timeout, factory, Arc::new(SystemClock))
+    }
+    pub fn with_clock<F>(shards: usize, idle_timeout: Duration, factory: F, clock: Arc<dyn Clock>) -> Self where F: Fn() -> L + Send + Sync + 'static {
+        assert!(shards > 0);
+
+        let shards = (0..shards)
+            .map(|_| Mutex::new(Shard { limiters: HashMap::new(), next_sweep: clock.now() + idle_timeout }))
+            .collect();
+        KeyedRateLimiter {
+            shards: Arc::new(shards),
+            factory: Arc::new(factory),
+            idle_timeout,
+            clock
+        }
+    }
+
+    pub fn try_acquire(&self, key: &K, permits: usize) -> Acquisition {
+        self.limiter(key).try_acquire(permits)
+    }
+
+    pub fn acquire_timeout(&self, key: &K, permits: usize, timeout: Duration) -> Acquisition {
+        self.limiter(key).acquire_timeout(permits, timeout)
+    }
+
+    // evicts all idle limiters, returns the number of evicted limiters
+    pub fn evict_idle(&self) -> usize {
+        let now = self.clock.now();
+        self.shards.iter()
+            .map(|shard| self.sweep(&mut shard.lock().unwrap(), now))
+            .sum()
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.lock().unwrap().limiters.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // limiters share their state between clones, so the shard lock is not held while acquiring
+    pub fn limiter(&self, key: &K) -> L {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let idx = (hasher.finish() % self.shards.len() as u64) as usize;
+        let mut shard = self.shards[idx].lock().unwrap();
+
+        let now = self.clock.now();
+        if now >= shard.next_sweep {
+            self.sweep(&mut shard, now);
+        }
+        let entry = shard.limiters.entry(key.clone())
+            .or_insert_with(|| KeyedEntry { limiter: (self.factory)(), last_access: now });
+        entry.last_access = now;
+        entry.limiter.clone()
+    }
+
+    fn sweep(&self, shard: &mut Shard<K, L>, now: Instant) -> usize {
+        let len = shard.limiters.len();
+        shard.limiters.retain(|_, entry| now.saturating_duration_since(entry.last_access) < self.idle_timeout);
+        shard.next_sweep = now + self.idle_timeout;
+        len - shard.limiters.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::thread::JoinHandle;
+    use crate::clock::MockClock;
+    use crate::rate_limiting::{FixedWindow, TokenBucket};
+    use super::*;
+
+    #[test]
+    fn test_keys_limited_separately() {
+        let limiter = KeyedRateLimiter::new(4, Duration::from_secs(10), || FixedWindow::new(2, Duration::from_secs(1)));
+        assert!(limiter.try_acquire(&"a", 1).is_granted());
+        assert!(limiter.try_acquire(&"a", 1).is_granted());
+        assert!(!limiter.try_acquire(&"a", 1).is_granted());
+        // another client still has its own quota
+        assert!(limiter.try_acquire(&"b", 2).is_granted());
+        assert_eq!(limiter.len(), 2);
+    }
+
+    #[test]
+    fn test_idle_eviction() {
+        let clock = MockClock::new();
+        let limiter_clock = clock.clone();
+        let limiter = KeyedRateLimiter::with_clock(1, Duration::from_millis(50), move || {
+            TokenBucket::with_clock(10, Duration::from_millis(10), 10, Arc::new(limiter_clock.clone()))
+        }, Arc::new(clock.clone()));
+        for key in 0..10 {
+            limiter.try_acquire(&key, 1);
+        }
+        assert_eq!(limiter.len(), 10);
+
+        clock.advance(Duration::from_millis(60));
+        // accessing the shard sweeps the idle keys
+        limiter.try_acquire(&100, 1);
+        assert_eq!(limiter.len(), 1);
+
+        clock.advance(Duration::from_millis(60));
+        assert_eq!(limiter.evict_idle(), 1);
+        assert_eq!(limiter.len(), 0);
+    }
+
+    #[test]
+    fn test_multiple_threads() {
+        let limiter = KeyedRateLimiter::new(8, Duration::from_secs(10), || FixedWindow::new(100, Duration::from_secs(10)));
+        let tasks: Vec<JoinHandle<usize>> = (0..8).map(|idx| (idx, limiter.clone()))
+            .map(|(idx, limiter)| {
+                thread::spawn(move || {
+                    (0..200).filter(|_| limiter.try_acquire(&idx, 1).is_granted()).count()
+                })
+            }).collect();
+
+        tasks.into_iter().for_each(|thread| {
+            assert_eq!(thread.join().unwrap(), 100);
+        });
+        assert_eq!(limiter.len(), 8);
+    }
+
+}
@@ -0,0 +1,123 @@
+use std::time::Duration;
+use crate::circuit_breaker::{CircuitBreaker, Error, Windowing, WindowingCount};
+use crate::rate_limiting::{Acquisition, RateLimiter};
+use crate::retry::Retry;
+
+/*
+   A call decorated with a retry policy, a rate limiter and a circuit breaker, each of them optional.
+   They are applied in the order Retry ( RateLimiter ( CircuitBreaker ( call ) ) ):
+   every attempt needs a permit, the permit is released if the circuit breaker rejects the call,
+   and the retry policy sees the errors of all of them.
+ */
+pub struct Pipeline<E, W: Windowing = WindowingCount> {
+    retry: Option<Retry<Error<E>>>,
+    // the limiter and how long an attempt waits for a permit
+    rate_limiter: Option<(Box<dyn RateLimiter + Send + Sync>, Duration)>,
+    circuit_breaker: Option<CircuitBreaker<W>>
+}
+
+impl <E, W: Windowing> Default for Pipeline<E, W> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl <E, W: Windowing> Pipeline<E, W> {
+    pub fn new() -> Self {
+        Pipeline {
+            retry: None,
+            rate_limiter: None,
+            circuit_breaker: None
+        }
+    }
+
+    pub fn retry(mut self, retry: Retry<Error<E>>) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    pub fn rate_limiter<L>(mut self, rate_limiter: L, timeout: Duration) -> Self where L: RateLimiter + Send + Sync + 'static {
+        self.rate_limiter = Some((Box::new(rate_limiter), timeout));
+        self
+    }
+
+    pub fn circuit_breaker(mut self, circuit_breaker: CircuitBreaker<W>) -> Self {
+        self.circuit_breaker = Some(circuit_breaker);
+        self
+    }
+
+    pub fn execute<F, R: 'static>(&self, mut f: F) -> Result<R, Error<E>> where F: FnMut() -> Result<R, E>, E: 'static {
+        match &self.retry {
+            Some(retry) => retry.execute(|| self.attempt(&mut f)),
+            None => self.attempt(&mut f)
+        }
+    }
+
+    fn attempt<F, R: 'static>(&self, f: &mut F) -> Result<R, Error<E>> where F: FnMut() -> Result<R, E>, E: 'static {
+        let mut acquired_at = None;
+        if let Some((rate_limiter, timeout)) = &self.rate_limiter {
+            let start = rate_limiter.clock().now();
+            acquired_at = match rate_limiter.acquire_timeout(1, *timeout) {
+                Acquisition::Granted => Some(start),
+                Acquisition::Queued(waited) => Some(start + waited),
+                Acquisition::Denied { retry_after } | Acquisition::TimedOut { retry_after } => return Err(Error::RateLimited { retry_after })
+            };
+        }
+        let result = match &self.circuit_breaker {
+            Some(circuit_breaker) => circuit_breaker.execute(&mut *f),
+            None => f().map_err(|e| Error::Custom(e))
+        };
+        // the call has not been made
+        if let (Err(Error::Rejected), Some((rate_limiter, _)), Some(acquired_at)) = (&result, &self.rate_limiter, acquired_at) {
+            rate_limiter.release(1, acquired_at);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use crate::backoff::Backoff;
+    use crate::clock::MockClock;
+    use crate::rate_limiting::FixedWindow;
+    use super::*;
+
+    #[test]
+    fn test_pipeline() {
+        let clock = MockClock::new();
+        let pipeline = Pipeline::new()
+            .retry(Retry::with_clock(3, Backoff::Fixed(Duration::from_secs(1)), Arc::new(clock.clone()))
+                .retry_on(|error: &Error<&str>| matches!(error, Error::Custom(_))))
+            .rate_limiter(FixedWindow::with_clock(2, Duration::from_secs(1), Arc::new(clock.clone())), Duration::ZERO)
+            .circuit_breaker(CircuitBreaker::<WindowingCount>::with_clock(10, 0.5, Duration::from_secs(60), Arc::new(clock.clone())).minimum_calls(5));
+
+        let mut attempts = 0;
+        let result = pipeline.execute(|| {
+            attempts += 1;
+            if attempts < 3 { Err("oops") } else { Ok(attempts) }
+        });
+        assert_eq!(result, Ok(3));
+        assert_eq!(clock.elapsed(), Duration::from_secs(2));
+
+        // both permits of the window are taken
+        pipeline.execute(|| { if 1 > 0 { Ok(1) } else { Err("oops") } });
+        assert_eq!(pipeline.execute(|| { if 1 > 0 { Ok(1) } else { Err("oops") } }), Err(Error::RateLimited { retry_after: Duration::from_secs(1) }));
+    }
+
+    #[test]
+    fn test_rejected_call_releases_permit() {
+        let rate_limiter = FixedWindow::new(1, Duration::from_secs(60));
+        let circuit_breaker = CircuitBreaker::<WindowingCount>::with_clock(10, 0.5, Duration::from_secs(60), Arc::new(MockClock::new()));
+        let pipeline = Pipeline::new()
+            .rate_limiter(rate_limiter.clone(), Duration::ZERO)
+            .circuit_breaker(circuit_breaker.clone());
+
+        circuit_breaker.force_open();
+        assert_eq!(pipeline.execute(|| { if 1 > 0 { Ok(1) } else { Err("oops") } }), Err(Error::Rejected));
+        circuit_breaker.force_close();
+        assert_eq!(pipeline.execute(|| { if 1 > 0 { Ok(1) } else { Err("oops") } }), Ok(1));
+        assert!(!rate_limiter.try_acquire(1).is_granted());
+    }
+
+}
@@ -0,0 +1,150 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+use data_structures::cache_stats::average;
+use crate::rate_limiting::Acquisition;
+
+/*
+   Counters of a rate limiter: granted requests, requests granted after queueing and rejected requests by reason.
+   Counters are atomic so a single instance can be recorded into from many threads.
+ */
+#[derive(Debug, Default)]
+pub struct RateLimiterMetrics {
+    granted: AtomicU64,
+    queued: AtomicU64,
+    total_queued_nanos: AtomicU64,
+    rejected_full: AtomicU64,
+    rejected_timeout: AtomicU64,
+    // requests currently waiting for permits
+    waiting: AtomicUsize
+}
+
+// A point-in-time copy of RateLimiterMetrics counters with the current state of the limiter.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct RateLimiterSnapshot {
+    // including the requests granted after queueing
+    pub granted: u64,
+    pub queued: u64,
+    pub total_queued_time: Duration,
+    // rejected without waiting: the limit is reached or the buffer is full
+    pub rejected_full: u64,
+    // rejected after waiting, or because the permits would not be available within the timeout
+    pub rejected_timeout: u64,
+    pub queue_depth: usize,
+    // the permits which can be acquired right now
+    pub available_permits: usize
+}
+
+impl RateLimiterMetrics {
+    pub fn new() -> Self {
+        RateLimiterMetrics::default()
+    }
+
+    // records the outcome and passes it through
+    pub fn record(&self, acquisition: Acquisition) -> Acquisition {
+        match acquisition {
+            Acquisition::Granted => {
+                self.granted.fetch_add(1, Ordering::Relaxed);
+            },
+            Acquisition::Queued(queued_time) => {
+                self.granted.fetch_add(1, Ordering::Relaxed);
+                self.queued.fetch_add(1, Ordering::Relaxed);
+                self.total_queued_nanos.fetch_add(queued_time.as_nanos() as u64, Ordering::Relaxed);
+            },
+            Acquisition::Denied { .. } => {
+                self.rejected_full.fetch_add(1, Ordering::Relaxed);
+            },
+            Acquisition::TimedOut { .. } => {
+                self.rejected_timeout.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        acquisition
+    }
+
+    // un-records a grant which was withdrawn after it was recorded
+    pub fn revoke(&self) {
+        self.granted.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn start_waiting(&self) {
+        self.waiting.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stop_waiting(&self) {
+        self.waiting.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn waiting(&self) -> usize {
+        self.waiting.load(Ordering::Relaxed)
+    }
+
+    pub fn snapshot(&self, queue_depth: usize, available_permits: usize) -> RateLimiterSnapshot {
+        RateLimiterSnapshot {
+            granted: self.granted.load(Ordering::Relaxed),
+            queued: self.queued.load(Ordering::Relaxed),
+            total_queued_time: Duration::from_nanos(self.total_queued_nanos.load(Ordering::Relaxed)),
+            rejected_full: self.rejected_full.load(Ordering::Relaxed),
+            rejected_timeout: self.rejected_timeout.load(Ordering::Relaxed),
+            queue_depth,
+            available_permits
+        }
+    }
+
+    pub fn reset(&self) {
+        self.granted.store(0, Ordering::Relaxed);
+        self.queued.store(0, Ordering::Relaxed);
+        self.total_queued_nanos.store(0, Ordering::Relaxed);
+        self.rejected_full.store(0, Ordering::Relaxed);
+        self.rejected_timeout.store(0, Ordering::Relaxed);
+    }
+}
+
+impl RateLimiterSnapshot {
+    pub fn requests(&self) -> u64 {
+        self.granted + self.rejected()
+    }
+
+    pub fn rejected(&self) -> u64 {
+        self.rejected_full + self.rejected_timeout
+    }
+
+    pub fn rejection_ratio(&self) -> f64 {
+        if self.requests() == 0 {
+            0.0
+        } else {
+            self.rejected() as f64 / self.requests() as f64
+        }
+    }
+
+    pub fn average_queued_time(&self) -> Duration {
+        average(self.total_queued_time, self.queued)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot() {
+        let metrics = RateLimiterMetrics::new();
+        metrics.record(Acquisition::Granted);
+        metrics.record(Acquisition::Queued(Duration::from_millis(10)));
+        metrics.record(Acquisition::Queued(Duration::from_millis(30)));
+        metrics.record(Acquisition::Denied { retry_after: Duration::from_millis(5) });
+        metrics.record(Acquisition::TimedOut { retry_after: Duration::from_millis(5) });
+        metrics.start_waiting();
+
+        let snapshot = metrics.snapshot(metrics.waiting(), 7);
+        assert_eq!(snapshot.granted, 3);
+        assert_eq!(snapshot.queued, 2);
+        assert_eq!(snapshot.average_queued_time(), Duration::from_millis(20));
+        assert_eq!(snapshot.rejected(), 2);
+        assert_eq!(snapshot.requests(), 5);
+        assert_eq!(snapshot.rejection_ratio(), 0.4);
+        assert_eq!((snapshot.queue_depth, snapshot.available_permits), (1, 7));
+
+        metrics.reset();
+        assert_eq!(metrics.snapshot(0, 0), RateLimiterSnapshot::default());
+    }
+
+}
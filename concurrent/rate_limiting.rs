@@ -1,11 +1,92 @@
 use std::thread;
-use std::ops::{AddAssign, Div, Sub};
+use std::ops::Div;
 use std::sync::{Arc, Mutex, Condvar};
-use std::sync::atomic::AtomicUsize;
 use std::time::{Duration, Instant};
+use crate::clock::{Clock, SystemClock};
+use crate::rate_limiter_metrics::{RateLimiterMetrics, RateLimiterSnapshot};
 
 // Rate Limiting algorithms: LeakyBucket, TokenBucket, FixedWindow, SlidingWindow
 
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Acquisition {
+    Granted,
+    // granted after waiting for the given time
+    Queued(Duration),
+    // the limit is reached or the buffer is full
+    // the permits might be available after retry_after, Duration::MAX if they can never be granted
+    Denied { retry_after: Duration },
+    // the permits were not available within the timeout
+    TimedOut { retry_after: Duration }
+}
+
+impl Acquisition {
+    pub fn is_granted(&self) -> bool {
+        matches!(self, Acquisition::Granted | Acquisition::Queued(_))
+    }
+}
+
+/*
+   A common interface of all rate limiters.
+   Requests can be charged a different number of permits depending on their cost.
+ */
+pub trait RateLimiter {
+    // non-blocking
+    fn try_acquire(&self, permits: usize) -> Acquisition;
+
+    // gives back permits acquired but not used, e.g. when the call is rejected before it is made
+    // acquired_at is read from the limiter clock before acquiring, so it is never later than the grant
+    // window limiters keep the permits granted before their current window, that window has already been reset
+    fn release(&self, permits: usize, acquired_at: Instant);
+
+    // gives back the permits of a grant which is withdrawn, e.g. when a request is rejected by another limiter
+    // unlike release, the withdrawn grant is no longer counted by the metrics
+    fn revoke(&self, permits: usize, acquired_at: Instant) {
+        self.release(permits, acquired_at);
+        if let Some(metrics) = self.metrics() {
+            metrics.revoke();
+        }
+    }
+
+    // blocking up to the timeout
+    fn acquire_timeout(&self, permits: usize, timeout: Duration) -> Acquisition {
+        acquire_with_retries(self.clock(), timeout, None, || self.try_acquire(permits))
+    }
+
+    // the clock measuring the time of the limiter
+    fn clock(&self) -> &dyn Clock {
+        &SystemClock
+    }
+
+    // the metrics recorded by the limiter, if it keeps any
+    fn metrics(&self) -> Option<&RateLimiterMetrics> {
+        None
+    }
+}
+
+// retries until the permits are granted, gives up if they will not be available within the timeout
+fn acquire_with_retries<F>(clock: &dyn Clock, timeout: Duration, metrics: Option<&RateLimiterMetrics>, try_acquire: F) -> Acquisition
+    where F: Fn() -> Acquisition {
+    let start = clock.now();
+    let mut queued = false;
+    loop {
+        let waited = clock.now().saturating_duration_since(start);
+        match try_acquire() {
+            Acquisition::Granted if queued => return Acquisition::Queued(waited),
+            Acquisition::Denied { retry_after } if retry_after != Duration::MAX && !timeout.is_zero() => {
+                if retry_after > timeout.saturating_sub(waited) {
+                    return Acquisition::TimedOut { retry_after };
+                }
+                metrics.inspect(|metrics| metrics.start_waiting());
+                // the clock has to move before the next attempt, otherwise a zero retry_after is retried forever
+                clock.sleep(retry_after.max(Duration::from_nanos(1)));
+                metrics.inspect(|metrics| metrics.stop_waiting());
+                queued = true;
+            },
+            acquisition => return acquisition
+        }
+    }
+}
+
 struct LeakyBucketData {
     next_available_time: Instant,
     curr_buffer_size: usize
@@ -22,7 +103,9 @@ pub struct LeakyBucket {
     condvar: Arc<Condvar>,
     wait_interval: Duration,
     wait_timeout: Duration,
-    buffer_size: usize
+    buffer_size: usize,
+    metrics: Arc<RateLimiterMetrics>,
+    clock: Arc<dyn Clock>
 }
 
 impl Clone for LeakyBucket {
@@ -32,47 +115,113 @@ impl Clone for LeakyBucket {
             condvar: Arc::clone(&self.condvar),
             wait_interval: self.wait_interval.clone(),
             wait_timeout: self.wait_timeout.clone(),
-            buffer_size: self.buffer_size.clone()
+            buffer_size: self.buffer_size.clone(),
+            metrics: Arc::clone(&self.metrics),
+            clock: Arc::clone(&self.clock)
         }
     }
 }
 
 impl LeakyBucket {
     pub fn new(max_requests_per_interval: usize, refresh_interval: Duration, buffer_size: usize, wait_timeout: Duration) -> Self {
+        Self::with_clock(max_requests_per_interval, refresh_interval, buffer_size, wait_timeout, Arc::new(SystemClock))
+    }
+    pub fn with_clock(max_requests_per_interval: usize, refresh_interval: Duration, buffer_size: usize, wait_timeout: Duration, clock: Arc<dyn Clock>) -> Self {
         assert!(max_requests_per_interval > 0);
 
         let wait_interval = refresh_interval.div(max_requests_per_interval as u32);
         LeakyBucket {
             data: Arc::new(Mutex::new(LeakyBucketData {
-                next_available_time: Instant::now(),
+                next_available_time: clock.now(),
                 curr_buffer_size: 0
             })),
             condvar: Arc::new(Condvar::new()),
             wait_interval,
             wait_timeout,
-            buffer_size
+            buffer_size,
+            metrics: Arc::new(RateLimiterMetrics::new()),
+            clock
         }
     }
     pub fn acquire(&self) -> bool {
+        self.acquire_timeout(1, self.wait_timeout).is_granted()
+    }
+
+    // the time the permits take to leak out, None if it does not fit in a Duration
+    fn leak_time(&self, permits: usize) -> Option<Duration> {
+        u32::try_from(permits).ok().and_then(|permits| self.wait_interval.checked_mul(permits))
+    }
+
+    // a leaky bucket lets one request through at a time, so at most 1 permit is available
+    pub fn snapshot(&self) -> RateLimiterSnapshot {
+        let data = self.data.lock().unwrap();
+        let available_permits = if self.clock.now() >= data.next_available_time { 1 } else { 0 };
+        self.metrics.snapshot(data.curr_buffer_size, available_permits)
+    }
+}
+
+impl RateLimiter for LeakyBucket {
+    fn try_acquire(&self, permits: usize) -> Acquisition {
+        self.acquire_timeout(permits, Duration::ZERO)
+    }
+
+    // waiting requests are held in the buffer, if the buffer is full the request is denied immediately
+    fn acquire_timeout(&self, permits: usize, timeout: Duration) -> Acquisition {
+        let leak_time = match self.leak_time(permits) {
+            Some(leak_time) => leak_time,
+            None => return self.metrics.record(Acquisition::Denied { retry_after: Duration::MAX })
+        };
         let mut data = self.data.lock().unwrap();
 
-        let mut wait_timeout = self.wait_timeout;
+        let start = self.clock.now();
+        let mut queued = false;
         loop {
-            let now = Instant::now();
+            let now = self.clock.now();
+            let waited = now.saturating_duration_since(start);
+            let wait_timeout = timeout.saturating_sub(waited);
             if now >= data.next_available_time {
-                data.next_available_time.add_assign(self.wait_interval);
-                return true;
+                // the next requests have to wait until all the acquired permits leak out
+                // the time left idle is not saved up, so at most one request gets through at once
+                data.next_available_time = data.next_available_time.max(now) + leak_time;
+                let acquisition = if queued { Acquisition::Queued(waited) } else { Acquisition::Granted };
+                return self.metrics.record(acquisition);
             } else if data.curr_buffer_size < self.buffer_size && !wait_timeout.is_zero() {
                 data.curr_buffer_size += 1;
+                queued = true;
                 let wait_time = (data.next_available_time.saturating_duration_since(now)).min(wait_timeout);
-                wait_timeout = wait_timeout.saturating_sub(wait_time);
-                data = self.condvar.wait_timeout(data, wait_time).unwrap().0;
+                data = self.condvar.wait_timeout(data, self.clock.block_timeout(wait_time)).unwrap().0;
                 data.curr_buffer_size -= 1;
             } else {
-                return false;
+                let retry_after = data.next_available_time.saturating_duration_since(now);
+                let acquisition = if !timeout.is_zero() && wait_timeout.is_zero() {
+                    Acquisition::TimedOut { retry_after }
+                } else {
+                    Acquisition::Denied { retry_after }
+                };
+                return self.metrics.record(acquisition);
             }
         }
     }
+
+    fn release(&self, permits: usize, _acquired_at: Instant) {
+        // permits which do not fit were never granted
+        let leak_time = match self.leak_time(permits) {
+            Some(leak_time) => leak_time,
+            None => return
+        };
+        let mut data = self.data.lock().unwrap();
+        let next_available_time = data.next_available_time.checked_sub(leak_time).unwrap_or(data.next_available_time);
+        data.next_available_time = next_available_time.max(self.clock.now());
+        self.condvar.notify_all();
+    }
+
+    fn clock(&self) -> &dyn Clock {
+        self.clock.as_ref()
+    }
+
+    fn metrics(&self) -> Option<&RateLimiterMetrics> {
+        Some(self.metrics.as_ref())
+    }
 }
 
 //
@@ -95,7 +244,9 @@ pub struct TokenBucket {
     condvar: Arc<Condvar>,
     tokens_per_interval: usize,
     refill_interval: Duration,
-    buffer_size: usize
+    buffer_size: usize,
+    metrics: Arc<RateLimiterMetrics>,
+    clock: Arc<dyn Clock>
 }
 
 impl Clone for TokenBucket {
@@ -105,44 +256,101 @@ impl Clone for TokenBucket {
             condvar: Arc::clone(&self.condvar),
             tokens_per_interval: self.tokens_per_interval.clone(),
             refill_interval: self.refill_interval.clone(),
-            buffer_size: self.buffer_size.clone()
+            buffer_size: self.buffer_size.clone(),
+            metrics: Arc::clone(&self.metrics),
+            clock: Arc::clone(&self.clock)
         }
     }
 }
 
 impl TokenBucket {
     pub fn new(tokens_per_interval: usize, refill_interval: Duration, buffer_size: usize) -> Self {
+        Self::with_clock(tokens_per_interval, refill_interval, buffer_size, Arc::new(SystemClock))
+    }
+    pub fn with_clock(tokens_per_interval: usize, refill_interval: Duration, buffer_size: usize, clock: Arc<dyn Clock>) -> Self {
         assert!(tokens_per_interval > 0);
 
         TokenBucket {
             data: Arc::new(Mutex::new(TokenBucketData {
-                next_refill_time: Instant::now(),
+                next_refill_time: clock.now(),
                 tokens_count: 0
             })),
             condvar: Arc::new(Condvar::new()),
             tokens_per_interval,
             refill_interval,
-            buffer_size
+            buffer_size,
+            metrics: Arc::new(RateLimiterMetrics::new()),
+            clock
         }
     }
     pub fn acquire(&self) -> bool {
+        self.try_acquire(1).is_granted()
+    }
+
+    pub fn snapshot(&self) -> RateLimiterSnapshot {
         let mut data = self.data.lock().unwrap();
+        self.refill(&mut data, self.clock.now());
+        self.metrics.snapshot(self.metrics.waiting(), data.tokens_count)
+    }
 
-        if Instant::now() > data.next_refill_time {
-            // refill
-            data.tokens_count = (data.tokens_count + self.tokens_per_interval).min(self.buffer_size);
-            data.next_refill_time += self.refill_interval;
+    fn refill(&self, data: &mut TokenBucketData, now: Instant) {
+        if now >= data.next_refill_time {
+            // refill for every interval passed since the last refill
+            let intervals = (now - data.next_refill_time).as_nanos() / self.refill_interval.as_nanos() + 1;
+            let tokens = (self.tokens_per_interval as u128 * intervals).min(self.buffer_size as u128) as usize;
+            data.tokens_count = data.tokens_count.saturating_add(tokens).min(self.buffer_size);
+            data.next_refill_time += Duration::from_nanos((self.refill_interval.as_nanos() * intervals) as u64);
         }
+    }
+
+    fn acquire_now(&self, permits: usize) -> Acquisition {
+        let mut data = self.data.lock().unwrap();
+
+        let now = self.clock.now();
+        self.refill(&mut data, now);
 
-        if data.tokens_count > 0 {
-            data.tokens_count -= 1;
-            true
+        if data.tokens_count >= permits {
+            data.tokens_count -= permits;
+            Acquisition::Granted
+        } else if permits > self.buffer_size {
+            Acquisition::Denied { retry_after: Duration::MAX }
         } else {
-            false
+            // wait for as many refills as needed to collect the missing tokens
+            // Duration::MAX if the refills take longer than a Duration holds
+            let refills = (permits - data.tokens_count).div_ceil(self.tokens_per_interval);
+            let retry_after = u32::try_from(refills - 1).ok()
+                .and_then(|refills| self.refill_interval.checked_mul(refills))
+                .and_then(|refill_time| data.next_refill_time.saturating_duration_since(now).checked_add(refill_time))
+                .unwrap_or(Duration::MAX);
+            Acquisition::Denied { retry_after }
         }
     }
 }
 
+impl RateLimiter for TokenBucket {
+    fn try_acquire(&self, permits: usize) -> Acquisition {
+        self.metrics.record(self.acquire_now(permits))
+    }
+
+    fn acquire_timeout(&self, permits: usize, timeout: Duration) -> Acquisition {
+        let acquisition = acquire_with_retries(self.clock.as_ref(), timeout, Some(&self.metrics), || self.acquire_now(permits));
+        self.metrics.record(acquisition)
+    }
+
+    fn release(&self, permits: usize, _acquired_at: Instant) {
+        let mut data = self.data.lock().unwrap();
+        data.tokens_count = data.tokens_count.saturating_add(permits).min(self.buffer_size);
+    }
+
+    fn clock(&self) -> &dyn Clock {
+        self.clock.as_ref()
+    }
+
+    fn metrics(&self) -> Option<&RateLimiterMetrics> {
+        Some(self.metrics.as_ref())
+    }
+}
+
 //
 
 struct FixedWindowData {
@@ -159,7 +367,9 @@ struct FixedWindowData {
 pub struct FixedWindow {
     data: Arc<Mutex<FixedWindowData>>,
     max_request_per_interval: usize,
-    refresh_interval: Duration
+    refresh_interval: Duration,
+    metrics: Arc<RateLimiterMetrics>,
+    clock: Arc<dyn Clock>
 }
 
 impl Clone for FixedWindow {
@@ -167,41 +377,93 @@ impl Clone for FixedWindow {
         FixedWindow {
             data: Arc::clone(&self.data),
             max_request_per_interval: self.max_request_per_interval.clone(),
-            refresh_interval: self.refresh_interval.clone()
+            refresh_interval: self.refresh_interval.clone(),
+            metrics: Arc::clone(&self.metrics),
+            clock: Arc::clone(&self.clock)
         }
     }
 }
 
 impl FixedWindow {
     pub fn new(max_request_per_interval: usize, refresh_interval: Duration) -> Self {
+        Self::with_clock(max_request_per_interval, refresh_interval, Arc::new(SystemClock))
+    }
+    pub fn with_clock(max_request_per_interval: usize, refresh_interval: Duration, clock: Arc<dyn Clock>) -> Self {
         assert!(max_request_per_interval > 0);
 
         FixedWindow {
             data: Arc::new(Mutex::new(FixedWindowData {
                 counter: 0,
-                interval_start_time: Instant::now()
+                interval_start_time: clock.now()
             })),
             max_request_per_interval,
-            refresh_interval
+            refresh_interval,
+            metrics: Arc::new(RateLimiterMetrics::new()),
+            clock
         }
     }
     pub fn acquire(&self) -> bool {
+        self.try_acquire(1).is_granted()
+    }
+
+    pub fn snapshot(&self) -> RateLimiterSnapshot {
+        let data = self.data.lock().unwrap();
+        let available_permits = if self.clock.now() - data.interval_start_time >= self.refresh_interval {
+            self.max_request_per_interval
+        } else {
+            self.max_request_per_interval.saturating_sub(data.counter)
+        };
+        self.metrics.snapshot(self.metrics.waiting(), available_permits)
+    }
+
+    fn acquire_now(&self, permits: usize) -> Acquisition {
         let mut data = self.data.lock().unwrap();
 
-        let now = Instant::now();
+        let now = self.clock.now();
 
-        // if we passed the current window, move to another window
-        if now - data.interval_start_time > self.refresh_interval {
-            data.counter = 1;
+        // if we reached the end of the current window, move to another window
+        if now - data.interval_start_time >= self.refresh_interval {
+            data.counter = 0;
             data.interval_start_time = now;
-            true
-        } else if data.counter < self.max_request_per_interval {
-            data.counter += 1;
-            true
+        }
+
+        if data.counter + permits <= self.max_request_per_interval {
+            data.counter += permits;
+            Acquisition::Granted
+        } else if permits > self.max_request_per_interval {
+            Acquisition::Denied { retry_after: Duration::MAX }
         } else {
-            false
+            // wait for the next window
+            let window_end = data.interval_start_time + self.refresh_interval;
+            Acquisition::Denied { retry_after: window_end.saturating_duration_since(now) }
+        }
+    }
+}
+
+impl RateLimiter for FixedWindow {
+    fn try_acquire(&self, permits: usize) -> Acquisition {
+        self.metrics.record(self.acquire_now(permits))
+    }
+
+    fn acquire_timeout(&self, permits: usize, timeout: Duration) -> Acquisition {
+        let acquisition = acquire_with_retries(self.clock.as_ref(), timeout, Some(&self.metrics), || self.acquire_now(permits));
+        self.metrics.record(acquisition)
+    }
+
+    fn release(&self, permits: usize, acquired_at: Instant) {
+        let mut data = self.data.lock().unwrap();
+        if acquired_at >= data.interval_start_time {
+            data.counter = data.counter.saturating_sub(permits);
         }
     }
+
+    fn clock(&self) -> &dyn Clock {
+        self.clock.as_ref()
+    }
+
+    fn metrics(&self) -> Option<&RateLimiterMetrics> {
+        Some(self.metrics.as_ref())
+    }
 }
 
 //
@@ -218,12 +480,19 @@ struct SlidingWindowData {
    every window, the counter information from the previous window is used to estimate
    the size of the counter in the current window.
    Instead of fixed window size, there is a rolling window of time to smooth bursts.
+   Only the counters of the current and the previous window are kept, so the memory is O(1) regardless of the limit.
+   The estimate assumes the requests of the previous window were spread evenly.
+   In the worst case, when they all arrived at the very end of the previous window, up to
+   max_request_per_interval * (1 + r) requests are granted within one interval, where r is the elapsed part of the current window,
+   so never more than twice the limit. Requests bunched at the start of the previous window are overestimated and denied instead.
    The implementation is thread-safe.
  */
 pub struct SlidingWindow {
     data: Arc<Mutex<SlidingWindowData>>,
     max_request_per_interval: usize,
-    interval: Duration
+    interval: Duration,
+    metrics: Arc<RateLimiterMetrics>,
+    clock: Arc<dyn Clock>
 }
 
 impl Clone for SlidingWindow {
@@ -231,51 +500,118 @@ impl Clone for SlidingWindow {
         SlidingWindow {
             data: Arc::clone(&self.data),
             max_request_per_interval: self.max_request_per_interval.clone(),
-            interval: self.interval.clone()
+            interval: self.interval.clone(),
+            metrics: Arc::clone(&self.metrics),
+            clock: Arc::clone(&self.clock)
         }
     }
 }
 
 impl SlidingWindow {
     pub fn new(max_request_per_interval: usize, interval: Duration) -> Self {
+        Self::with_clock(max_request_per_interval, interval, Arc::new(SystemClock))
+    }
+    pub fn with_clock(max_request_per_interval: usize, interval: Duration, clock: Arc<dyn Clock>) -> Self {
         assert!(max_request_per_interval > 0);
 
         SlidingWindow {
             data: Arc::new(Mutex::new(SlidingWindowData {
                 counter: 0,
                 prev_counter: max_request_per_interval,
-                interval_start_time: Instant::now()
+                interval_start_time: clock.now()
             })),
             max_request_per_interval,
-            interval
+            interval,
+            metrics: Arc::new(RateLimiterMetrics::new()),
+            clock
         }
     }
     pub fn acquire(&self) -> bool {
+        self.try_acquire(1).is_granted()
+    }
+
+    pub fn snapshot(&self) -> RateLimiterSnapshot {
         let mut data = self.data.lock().unwrap();
+        let since_last_interval = self.roll(&mut data, self.clock.now());
+        let available_permits = self.max_request_per_interval.saturating_sub(self.estimate(&data, since_last_interval));
+        self.metrics.snapshot(self.metrics.waiting(), available_permits)
+    }
 
-        let now = Instant::now();
+    // moves to another window if we passed the current one, returns the time since the current window start
+    fn roll(&self, data: &mut SlidingWindowData, now: Instant) -> Duration {
         let since_last_interval = now.saturating_duration_since(data.interval_start_time);
-
-        // if we passed the current window, move to another window
-        if since_last_interval >= self.interval {
-            data.prev_counter = data.counter;
-            data.counter = 0;
-            data.interval_start_time = now;
+        if since_last_interval < self.interval {
+            return since_last_interval;
         }
+        // the previous window is empty if more than one window has passed
+        data.prev_counter = if since_last_interval >= self.interval * 2 { 0 } else { data.counter };
+        data.counter = 0;
+        // the windows stay aligned, so the previous window decays from its end rather than from the first request after it
+        let since_window_start = Duration::from_nanos((since_last_interval.as_nanos() % self.interval.as_nanos()) as u64);
+        data.interval_start_time = now - since_window_start;
+        since_window_start
+    }
 
+    // the estimated counter of the sliding window
+    fn estimate(&self, data: &SlidingWindowData, since_last_interval: Duration) -> usize {
         // counter ratio from the previous window
-        let prev_window_counter_ratio = 1.0 - since_last_interval.as_nanos() as f32 / self.interval.as_nanos() as f32;
+        let prev_window_counter_ratio = 1.0 - since_last_interval.as_nanos() as f64 / self.interval.as_nanos() as f64;
         // counter from the previous window based on the ratio
-        let prev_window_counter = (prev_window_counter_ratio * data.prev_counter as f32) as usize;
-        let counter = prev_window_counter + data.counter;
+        let prev_window_counter = (prev_window_counter_ratio * data.prev_counter as f64) as usize;
+        prev_window_counter + data.counter
+    }
 
-        if counter < self.max_request_per_interval {
-            data.counter += 1;
-            true
+    fn acquire_now(&self, permits: usize) -> Acquisition {
+        let mut data = self.data.lock().unwrap();
+
+        let now = self.clock.now();
+        let since_last_interval = self.roll(&mut data, now);
+        let counter = self.estimate(&data, since_last_interval);
+
+        if counter + permits <= self.max_request_per_interval {
+            data.counter += permits;
+            Acquisition::Granted
+        } else if permits > self.max_request_per_interval {
+            Acquisition::Denied { retry_after: Duration::MAX }
+        } else if data.counter + permits <= self.max_request_per_interval {
+            // wait until the previous window counter decays enough
+            let allowed_prev_counter = (self.max_request_per_interval - data.counter - permits) as f64;
+            let ratio = 1.0 - allowed_prev_counter / data.prev_counter as f64;
+            let retry_at = data.interval_start_time + self.interval.mul_f64(ratio);
+            Acquisition::Denied { retry_after: retry_at.saturating_duration_since(now) }
         } else {
-            false
+            // wait for the next window, where the current counter decays enough
+            let ratio = 1.0 - (self.max_request_per_interval - permits) as f64 / data.counter as f64;
+            let retry_at = data.interval_start_time + self.interval + self.interval.mul_f64(ratio);
+            Acquisition::Denied { retry_after: retry_at.saturating_duration_since(now) }
+        }
+    }
+}
+
+impl RateLimiter for SlidingWindow {
+    fn try_acquire(&self, permits: usize) -> Acquisition {
+        self.metrics.record(self.acquire_now(permits))
+    }
+
+    fn acquire_timeout(&self, permits: usize, timeout: Duration) -> Acquisition {
+        let acquisition = acquire_with_retries(self.clock.as_ref(), timeout, Some(&self.metrics), || self.acquire_now(permits));
+        self.metrics.record(acquisition)
+    }
+
+    fn release(&self, permits: usize, acquired_at: Instant) {
+        let mut data = self.data.lock().unwrap();
+        if acquired_at >= data.interval_start_time {
+            data.counter = data.counter.saturating_sub(permits);
         }
     }
+
+    fn clock(&self) -> &dyn Clock {
+        self.clock.as_ref()
+    }
+
+    fn metrics(&self) -> Option<&RateLimiterMetrics> {
+        Some(self.metrics.as_ref())
+    }
 }
 
 static RUNTIME_SECS: u64 = 5;
@@ -368,8 +704,8 @@ fn main() {
 
 #[cfg(test)]
 mod tests {
-    use std::hint::spin_loop;
     use super::*;
+    use crate::clock::MockClock;
     use std::sync::Barrier;
     use std::time::Instant;
     use std::thread;
@@ -397,147 +733,302 @@ mod tests {
         assert!(start.elapsed().as_millis() >= 50);
     }
 
+    // acquires 500 permits, moving the clock by 1ms while the permits are denied
+    // returns the time between consecutive permits
+    fn acquire_500<F: Fn() -> bool>(clock: &MockClock, acquire: F) -> Vec<u128> {
+        let mut reqs_diff = Vec::new();
+        let mut time = clock.elapsed();
+        while reqs_diff.len() < 500 {
+            if acquire() {
+                reqs_diff.push((clock.elapsed() - time).as_millis());
+                time = clock.elapsed();
+            } else {
+                clock.advance(Duration::from_millis(1));
+            }
+        }
+        reqs_diff
+    }
+
     #[test]
     fn test_leaky_bucket() {
-        let leaky_bucket = LeakyBucket::new(10, Duration::from_millis(100), 10, Duration::from_millis(10));
-        let counter =  Arc::new(AtomicUsize::new(0));
-        let reqs_diff = Arc::new(Mutex::new((Vec::new(), Instant::now())));
-
-        (0..4).for_each(|_| {
-            let lb = leaky_bucket.clone();
-            let c = counter.clone();
-            let rd = reqs_diff.clone();
-            thread::spawn(move || {
-                loop {
-                    if lb.acquire() {
-                        c.fetch_add(1, Ordering::SeqCst);
-                        let (ref mut vec, ref mut time) = *rd.lock().unwrap();
-                        let duration = Instant::now().saturating_duration_since(*time).as_millis();
-                        *time = Instant::now();
-                        vec.push(duration);
-                    }
-                }
-            });
-        });
-
-        let start = Instant::now();
-        while counter.load(Ordering::SeqCst) < 500 {
-            spin_loop();
-        }
+        let clock = MockClock::new();
+        let leaky_bucket = LeakyBucket::with_clock(10, Duration::from_millis(100), 10, Duration::from_millis(10), Arc::new(clock.clone()));
+        let reqs_diff = acquire_500(&clock, || leaky_bucket.acquire());
 
         // 10 reqs per 100 ms
         // to get 500 reqs, we have to spend 500/10 = 50, 50 * 100ms = 5000ms
-        assert!(start.elapsed().as_millis() >= 4800 && start.elapsed().as_millis() <= 5200);
+        assert!(clock.elapsed().as_millis() >= 4800 && clock.elapsed().as_millis() <= 5200);
         // average difference between consecutive requests
-        let avg = reqs_diff.lock().unwrap().0.iter().map(|x| x.clone() as f32).reduce(|x, y| (x + y) / 2.0).unwrap();
+        let avg = reqs_diff.iter().map(|x| x.clone() as f32).reduce(|x, y| (x + y) / 2.0).unwrap();
         assert!(avg > 9.0 && avg < 11.0);
     }
 
     #[test]
     fn test_token_bucket() {
-        let token_bucket = TokenBucket::new(10, Duration::from_millis(100), 10);
-        let counter =  Arc::new(AtomicUsize::new(0));
-        let reqs_diff = Arc::new(Mutex::new((Vec::new(), Instant::now())));
-
-        (0..4).for_each(|_| {
-            let tb = token_bucket.clone();
-            let c = counter.clone();
-            let rd = reqs_diff.clone();
-            thread::spawn(move || {
-                loop {
-                    if tb.acquire() {
-                        c.fetch_add(1, Ordering::SeqCst);
-                        let (ref mut vec, ref mut time) = *rd.lock().unwrap();
-                        let duration = Instant::now().saturating_duration_since(*time).as_millis();
-                        *time = Instant::now();
-                        vec.push(duration);
-                    }
-                }
-            });
-        });
-
-        let start = Instant::now();
-        while counter.load(Ordering::SeqCst) < 500 {
-            spin_loop();
-        }
+        let clock = MockClock::new();
+        let token_bucket = TokenBucket::with_clock(10, Duration::from_millis(100), 10, Arc::new(clock.clone()));
+        let reqs_diff = acquire_500(&clock, || token_bucket.acquire());
 
         // 10 reqs refilled each 100 ms
         // to get 500 reqs, we have to spend 500/10 = 50, 50 * 100ms = 5000ms
-        assert!(start.elapsed().as_millis() >= 4800 && start.elapsed().as_millis() <= 5200);
+        assert!(clock.elapsed().as_millis() >= 4800 && clock.elapsed().as_millis() <= 5200);
         // average difference between consecutive requests
-        let avg = reqs_diff.lock().unwrap().0.iter().map(|x| x.clone() as f32).reduce(|x, y| (x + y) / 2.0).unwrap();
+        let avg = reqs_diff.iter().map(|x| x.clone() as f32).reduce(|x, y| (x + y) / 2.0).unwrap();
         assert!(avg < 1.0);
     }
 
     #[test]
     fn test_fixed_window() {
-        let fixed_window = FixedWindow::new(10, Duration::from_millis(100));
-        let counter =  Arc::new(AtomicUsize::new(0));
-        let reqs_diff = Arc::new(Mutex::new((Vec::new(), Instant::now())));
-
-        (0..4).for_each(|_| {
-            let fw = fixed_window.clone();
-            let c = counter.clone();
-            let rd = reqs_diff.clone();
-            thread::spawn(move || {
-                loop {
-                    if fw.acquire() {
-                        c.fetch_add(1, Ordering::SeqCst);
-                        let (ref mut vec, ref mut time) = *rd.lock().unwrap();
-                        let duration = Instant::now().saturating_duration_since(*time).as_millis();
-                        *time = Instant::now();
-                        vec.push(duration);
-                    }
-                }
-            });
-        });
-
-        let start = Instant::now();
-        while counter.load(Ordering::SeqCst) < 500 {
-            spin_loop();
-        }
+        let clock = MockClock::new();
+        let fixed_window = FixedWindow::with_clock(10, Duration::from_millis(100), Arc::new(clock.clone()));
+        let reqs_diff = acquire_500(&clock, || fixed_window.acquire());
 
         // 10 reqs refilled each 100 ms
         // to get 500 reqs, we have to spend 500/10 = 50, 50 * 100ms = 5000ms
-        assert!(start.elapsed().as_millis() >= 4800 && start.elapsed().as_millis() <= 5200);
+        assert!(clock.elapsed().as_millis() >= 4800 && clock.elapsed().as_millis() <= 5200);
         // average difference between consecutive requests
-        let avg = reqs_diff.lock().unwrap().0.iter().map(|x| x.clone() as f32).reduce(|x, y| (x + y) / 2.0).unwrap();
+        let avg = reqs_diff.iter().map(|x| x.clone() as f32).reduce(|x, y| (x + y) / 2.0).unwrap();
         assert!(avg < 1.0);
     }
 
     #[test]
-    fn test_sliding_window() {
-        let sliding_window = SlidingWindow::new(10, Duration::from_millis(100));
-        let counter =  Arc::new(AtomicUsize::new(0));
-        let reqs_diff = Arc::new(Mutex::new((Vec::new(), Instant::now())));
-
-        (0..4).for_each(|_| {
-            let sw = sliding_window.clone();
-            let c = counter.clone();
-            let rd = reqs_diff.clone();
-            thread::spawn(move || {
-                loop {
-                    if sw.acquire() {
-                        c.fetch_add(1, Ordering::SeqCst);
-                        let (ref mut vec, ref mut time) = *rd.lock().unwrap();
-                        let duration = Instant::now().saturating_duration_since(*time).as_millis();
-                        *time = Instant::now();
-                        vec.push(duration);
-                    }
-                }
-            });
-        });
+    fn test_fixed_window_boundary() {
+        let clock = MockClock::new();
+        let fixed_window = FixedWindow::with_clock(1, Duration::from_millis(100), Arc::new(clock.clone()));
+        assert!(fixed_window.try_acquire(1).is_granted());
+        assert_eq!(fixed_window.try_acquire(1), Acquisition::Denied { retry_after: Duration::from_millis(100) });
+        // the next window starts right at the end of the current one
+        assert_eq!(fixed_window.acquire_timeout(1, Duration::from_secs(1)), Acquisition::Queued(Duration::from_millis(100)));
+        clock.advance(Duration::from_millis(100));
+        assert!(fixed_window.try_acquire(1).is_granted());
+    }
 
-        let start = Instant::now();
-        while counter.load(Ordering::SeqCst) < 500 {
-            spin_loop();
-        }
+    #[test]
+    fn test_sliding_window() {
+        let clock = MockClock::new();
+        let sliding_window = SlidingWindow::with_clock(10, Duration::from_millis(100), Arc::new(clock.clone()));
+        let reqs_diff = acquire_500(&clock, || sliding_window.acquire());
 
         // 10 reqs refilled each 100 ms
         // to get 500 reqs, we have to spend 500/10 = 50, 50 * 100ms = 5000ms
-        assert!(start.elapsed().as_millis() >= 4800 && start.elapsed().as_millis() <= 5200);
+        assert!(clock.elapsed().as_millis() >= 4800 && clock.elapsed().as_millis() <= 5200);
         // average difference between consecutive requests
-        let avg = reqs_diff.lock().unwrap().0.iter().map(|x| x.clone() as f32).reduce(|x, y| (x + y) / 2.0).unwrap();
+        let avg = reqs_diff.iter().map(|x| x.clone() as f32).reduce(|x, y| (x + y) / 2.0).unwrap();
         assert!(avg > 9.0 && avg < 11.0);
     }
+
+    #[test]
+    fn test_sliding_window_worst_case_error() {
+        let clock = MockClock::new();
+        let sliding_window = SlidingWindow::with_clock(1_000_000, Duration::from_secs(60), Arc::new(clock.clone()));
+        // start with an empty previous window
+        clock.advance(Duration::from_secs(120));
+        sliding_window.try_acquire(0);
+
+        // the whole previous window arrives at its very end
+        clock.advance(Duration::from_millis(59_900));
+        assert!(sliding_window.try_acquire(1_000_000).is_granted());
+        // the next window starts
+        clock.advance(Duration::from_millis(100));
+        assert!(!sliding_window.acquire());
+
+        // half way through the window half of the previous window is counted,
+        // although all of it is still within the last minute
+        clock.advance(Duration::from_secs(30));
+        assert!(sliding_window.try_acquire(500_000).is_granted());
+        assert!(!sliding_window.acquire());
+    }
+
+    #[test]
+    fn test_sliding_window_retry_after() {
+        let clock = MockClock::new();
+        let sliding_window = SlidingWindow::with_clock(10, Duration::from_millis(100), Arc::new(clock.clone()));
+        // the previous window is empty, half of the current window has passed
+        clock.advance(Duration::from_millis(250));
+        assert!(sliding_window.try_acquire(10).is_granted());
+        // half of the current window decays in the middle of the next window, although it starts with the next request
+        assert_eq!(sliding_window.try_acquire(5), Acquisition::Denied { retry_after: Duration::from_millis(100) });
+        clock.advance(Duration::from_millis(100));
+        assert!(sliding_window.try_acquire(5).is_granted());
+        assert_eq!(sliding_window.try_acquire(1), Acquisition::Denied { retry_after: Duration::from_millis(10) });
+        assert_eq!(sliding_window.acquire_timeout(1, Duration::from_secs(1)), Acquisition::Queued(Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn test_multiple_threads() {
+        let clock = MockClock::new();
+        let limiters: Vec<(Arc<dyn RateLimiter + Send + Sync>, usize)> = vec![
+            // the leaky bucket lets one request through at a time
+            (Arc::new(LeakyBucket::with_clock(10, Duration::from_millis(100), 10, Duration::ZERO, Arc::new(clock.clone()))), 1),
+            (Arc::new(TokenBucket::with_clock(10, Duration::from_millis(100), 10, Arc::new(clock.clone()))), 10),
+            (Arc::new(FixedWindow::with_clock(10, Duration::from_millis(100), Arc::new(clock.clone()))), 10),
+            (Arc::new(SlidingWindow::with_clock(10, Duration::from_millis(100), Arc::new(clock.clone()))), 10)
+        ];
+        // the previous window of the sliding window is empty
+        clock.advance(Duration::from_millis(250));
+
+        // the clock does not move, so exactly the permits available now are granted
+        for (limiter, expected) in limiters {
+            let threads: Vec<thread::JoinHandle<usize>> = (0..4).map(|_| {
+                let l = limiter.clone();
+                thread::spawn(move || (0..100).filter(|_| l.try_acquire(1).is_granted()).count())
+            }).collect();
+            let granted: usize = threads.into_iter().map(|handle| handle.join().unwrap()).sum();
+            assert_eq!(granted, expected);
+        }
+    }
+
+    #[test]
+    fn test_leaky_bucket_weighted() {
+        let leaky_bucket = LeakyBucket::new(10, Duration::from_millis(100), 5, Duration::ZERO);
+        assert_eq!(leaky_bucket.try_acquire(5), Acquisition::Granted);
+        // 5 permits leak out in 50ms
+        match leaky_bucket.try_acquire(1) {
+            Acquisition::Denied { retry_after } => assert!(retry_after.as_millis() > 40 && retry_after.as_millis() <= 50),
+            acquisition => panic!("unexpected {:?}", acquisition)
+        }
+        let start = Instant::now();
+        assert!(matches!(leaky_bucket.acquire_timeout(1, Duration::from_millis(100)), Acquisition::Queued(_)));
+        assert!(start.elapsed().as_millis() >= 40);
+        // the permits would take longer to leak out than a Duration holds
+        assert_eq!(leaky_bucket.try_acquire(usize::MAX), Acquisition::Denied { retry_after: Duration::MAX });
+    }
+
+    #[test]
+    fn test_token_bucket_weighted() {
+        let token_bucket = TokenBucket::new(10, Duration::from_millis(100), 10);
+        assert_eq!(token_bucket.try_acquire(4), Acquisition::Granted);
+        assert_eq!(token_bucket.try_acquire(4), Acquisition::Granted);
+        match token_bucket.try_acquire(4) {
+            Acquisition::Denied { retry_after } => assert!(retry_after.as_millis() <= 100),
+            acquisition => panic!("unexpected {:?}", acquisition)
+        }
+        // more permits than the bucket can ever hold
+        assert_eq!(token_bucket.try_acquire(11), Acquisition::Denied { retry_after: Duration::MAX });
+        assert_eq!(token_bucket.acquire_timeout(11, Duration::from_millis(100)), Acquisition::Denied { retry_after: Duration::MAX });
+        assert!(matches!(token_bucket.acquire_timeout(4, Duration::from_millis(200)), Acquisition::Queued(_)));
+        // the refills would take longer than a Duration holds
+        let token_bucket = TokenBucket::new(1, Duration::from_secs(1), usize::MAX);
+        assert_eq!(token_bucket.try_acquire(usize::MAX), Acquisition::Denied { retry_after: Duration::MAX });
+    }
+
+    #[test]
+    fn test_fixed_window_weighted() {
+        let fixed_window = FixedWindow::new(5, Duration::from_millis(100));
+        assert_eq!(fixed_window.try_acquire(5), Acquisition::Granted);
+        match fixed_window.try_acquire(1) {
+            Acquisition::Denied { retry_after } => assert!(retry_after.as_millis() > 90 && retry_after.as_millis() <= 100),
+            acquisition => panic!("unexpected {:?}", acquisition)
+        }
+        assert!(matches!(fixed_window.acquire_timeout(1, Duration::from_millis(50)), Acquisition::TimedOut { .. }));
+        let start = Instant::now();
+        assert!(matches!(fixed_window.acquire_timeout(3, Duration::from_millis(100)), Acquisition::Queued(_)));
+        assert!(start.elapsed().as_millis() >= 40);
+    }
+
+    #[test]
+    fn test_sliding_window_weighted() {
+        let sliding_window = SlidingWindow::new(10, Duration::from_millis(100));
+        assert_eq!(sliding_window.try_acquire(1), Acquisition::Granted);
+        // the previous window is considered full at the start, so 5 more permits are released after 50-60ms
+        match sliding_window.try_acquire(5) {
+            Acquisition::Denied { retry_after } => assert!(retry_after.as_millis() >= 40 && retry_after.as_millis() <= 60),
+            acquisition => panic!("unexpected {:?}", acquisition)
+        }
+        let start = Instant::now();
+        assert!(matches!(sliding_window.acquire_timeout(5, Duration::from_millis(100)), Acquisition::Queued(_)));
+        assert!(start.elapsed().as_millis() >= 40);
+    }
+
+    #[test]
+    fn test_release() {
+        let token_bucket = TokenBucket::new(5, Duration::from_secs(1), 5);
+        let acquired_at = Instant::now();
+        assert!(token_bucket.try_acquire(5).is_granted());
+        token_bucket.release(2, acquired_at);
+        assert!(token_bucket.try_acquire(2).is_granted());
+        assert!(!token_bucket.acquire());
+
+        let fixed_window = FixedWindow::new(5, Duration::from_secs(1));
+        let acquired_at = Instant::now();
+        assert!(fixed_window.try_acquire(5).is_granted());
+        fixed_window.release(5, acquired_at);
+        assert!(fixed_window.try_acquire(5).is_granted());
+
+        let leaky_bucket = LeakyBucket::new(10, Duration::from_secs(1), 0, Duration::ZERO);
+        let acquired_at = Instant::now();
+        assert!(leaky_bucket.try_acquire(5).is_granted());
+        leaky_bucket.release(5, acquired_at);
+        assert!(leaky_bucket.acquire());
+    }
+
+    #[test]
+    fn test_release_after_rollover() {
+        let clock = MockClock::new();
+        let fixed_window = FixedWindow::with_clock(2, Duration::from_secs(1), Arc::new(clock.clone()));
+        let sliding_window = SlidingWindow::with_clock(2, Duration::from_secs(1), Arc::new(clock.clone()));
+        // the sliding window starts with a full previous window
+        clock.advance(Duration::from_secs(2));
+        let limiters: [&dyn RateLimiter; 2] = [&fixed_window, &sliding_window];
+        for limiter in limiters {
+            let acquired_at = clock.now();
+            assert!(limiter.try_acquire(2).is_granted());
+            clock.advance(Duration::from_secs(3));
+            assert!(limiter.try_acquire(2).is_granted());
+            // the permits of the previous window are not given back to the current one
+            limiter.release(2, acquired_at);
+            assert!(!limiter.try_acquire(2).is_granted());
+            limiter.release(2, clock.now());
+            assert!(limiter.try_acquire(2).is_granted());
+        }
+    }
+
+    #[test]
+    fn test_leaky_bucket_rejection_reasons() {
+        let clock = MockClock::new();
+        let leaky_bucket = LeakyBucket::with_clock(10, Duration::from_secs(1), 1, Duration::ZERO, Arc::new(clock.clone()));
+        assert_eq!(leaky_bucket.try_acquire(1), Acquisition::Granted);
+        // nothing waits in the buffer with a zero timeout
+        assert_eq!(leaky_bucket.try_acquire(1), Acquisition::Denied { retry_after: Duration::from_millis(100) });
+        // the mock clock moves while waiting in the buffer
+        assert_eq!(leaky_bucket.acquire_timeout(1, Duration::from_millis(50)), Acquisition::TimedOut { retry_after: Duration::from_millis(50) });
+        assert_eq!(leaky_bucket.acquire_timeout(1, Duration::from_millis(100)), Acquisition::Queued(Duration::from_millis(50)));
+
+        let snapshot = leaky_bucket.snapshot();
+        assert_eq!((snapshot.granted, snapshot.queued, snapshot.rejected_full, snapshot.rejected_timeout), (2, 1, 1, 1));
+        assert_eq!((snapshot.queue_depth, snapshot.available_permits), (0, 0));
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let clock = MockClock::new();
+        let token_bucket = TokenBucket::with_clock(10, Duration::from_secs(1), 10, Arc::new(clock.clone()));
+        assert!(token_bucket.try_acquire(8).is_granted());
+        assert!(!token_bucket.try_acquire(5).is_granted());
+        assert_eq!(token_bucket.acquire_timeout(5, Duration::from_millis(500)), Acquisition::TimedOut { retry_after: Duration::from_secs(1) });
+        assert_eq!(token_bucket.acquire_timeout(5, Duration::from_secs(2)), Acquisition::Queued(Duration::from_secs(1)));
+
+        let snapshot = token_bucket.snapshot();
+        assert_eq!((snapshot.granted, snapshot.queued, snapshot.rejected_full, snapshot.rejected_timeout), (2, 1, 1, 1));
+        assert_eq!(snapshot.average_queued_time(), Duration::from_secs(1));
+        assert_eq!((snapshot.queue_depth, snapshot.available_permits), (0, 5));
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(token_bucket.snapshot().available_permits, 10);
+
+        let fixed_window = FixedWindow::with_clock(10, Duration::from_secs(1), Arc::new(clock.clone()));
+        assert!(fixed_window.try_acquire(4).is_granted());
+        assert_eq!(fixed_window.snapshot().available_permits, 6);
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(fixed_window.snapshot().available_permits, 10);
+
+        let sliding_window = SlidingWindow::with_clock(10, Duration::from_secs(1), Arc::new(clock.clone()));
+        clock.advance(Duration::from_secs(2));
+        assert!(sliding_window.try_acquire(4).is_granted());
+        assert_eq!(sliding_window.snapshot().available_permits, 6);
+        // the next window starts, half of the previous window still counts after 500ms
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(sliding_window.snapshot().available_permits, 6);
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(sliding_window.snapshot().available_permits, 8);
+    }
 }
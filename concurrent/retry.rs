@@ -0,0 +1,142 @@
+use std::sync::Arc;
+use std::time::Duration;
+use crate::backoff::Backoff;
+use crate::clock::{Clock, SystemClock};
+
+type Retryable<E> = Arc<dyn Fn(&E) -> bool + Send + Sync>;
+
+/*
+   A retry policy: a failed call is repeated up to max_attempts times with the backoff delay between the attempts.
+   Only the errors matching the retryable predicate are retried, all errors by default.
+   With a deadline, the call is not retried if the next attempt would start after the deadline,
+   the last error is returned instead.
+ */
+pub struct Retry<E> {
+    max_attempts: u32,
+    backoff: Backoff,
+    retryable: Option<Retryable<E>>,
+    deadline: Option<Duration>,
+    clock: Arc<dyn Clock>
+}
+
+impl <E> Clone for Retry<E> {
+    fn clone(&self) -> Self {
+        Retry {
+            max_attempts: self.max_attempts,
+            backoff: self.backoff,
+            retryable: self.retryable.clone(),
+            deadline: self.deadline,
+            clock: Arc::clone(&self.clock)
+        }
+    }
+}
+
+impl <E> Retry<E> {
+    pub fn new(max_attempts: u32, backoff: Backoff) -> Self {
+        Self::with_clock(max_attempts, backoff, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(max_attempts: u32, backoff: Backoff, clock: Arc<dyn Clock>) -> Self {
+        assert!(max_attempts > 0);
+
+        Retry {
+            max_attempts,
+            backoff,
+            retryable: None,
+            deadline: None,
+            clock
+        }
+    }
+
+    pub fn retry_on<P>(mut self, retryable: P) -> Self where P: Fn(&E) -> bool + Send + Sync + 'static {
+        self.retryable = Some(Arc::new(retryable));
+        self
+    }
+
+    // the total time for all attempts
+    pub fn deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    fn is_retryable(&self, error: &E) -> bool {
+        self.retryable.as_ref().is_none_or(|retryable| retryable(error))
+    }
+
+    pub fn execute<F, R>(&self, mut f: F) -> Result<R, E> where F: FnMut() -> Result<R, E> {
+        let start = self.clock.now();
+        let mut delay = Duration::ZERO;
+        let mut attempt = 1;
+        loop {
+            match f() {
+                Err(error) if attempt < self.max_attempts && self.is_retryable(&error) => {
+                    delay = self.backoff.delay(attempt, delay);
+                    let next_attempt = self.clock.now().saturating_duration_since(start) + delay;
+                    if self.deadline.is_some_and(|deadline| next_attempt > deadline) {
+                        return Err(error);
+                    }
+                    self.clock.sleep(delay);
+                    attempt += 1;
+                },
+                result => return result
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backoff::ExponentialBackoff;
+    use crate::clock::MockClock;
+    use super::*;
+
+    #[test]
+    fn test_retry() {
+        let clock = MockClock::new();
+        let backoff = Backoff::Exponential(ExponentialBackoff::new(Duration::from_secs(1), 2.0, Duration::from_secs(60)));
+        let retry = Retry::with_clock(5, backoff, Arc::new(clock.clone()));
+
+        let mut attempts = 0;
+        let result = retry.execute(|| {
+            attempts += 1;
+            if attempts < 4 { Err("oops") } else { Ok(attempts) }
+        });
+        assert_eq!(result, Ok(4));
+        // 1 + 2 + 4 seconds between the attempts
+        assert_eq!(clock.elapsed(), Duration::from_secs(7));
+
+        let mut attempts = 0;
+        let result: Result<i32, &str> = retry.execute(|| {
+            attempts += 1;
+            Err("oops")
+        });
+        assert_eq!((result, attempts), (Err("oops"), 5));
+    }
+
+    #[test]
+    fn test_retryable() {
+        let retry = Retry::new(5, Backoff::Fixed(Duration::ZERO)).retry_on(|error: &&str| *error == "timeout");
+
+        let mut errors = vec!["invalid", "timeout", "timeout"];
+        let result: Result<i32, &str> = retry.execute(|| Err(errors.pop().unwrap()));
+        assert_eq!(result, Err("invalid"));
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn test_deadline() {
+        let clock = MockClock::new();
+        let retry = Retry::with_clock(10, Backoff::Fixed(Duration::from_secs(3)), Arc::new(clock.clone()))
+            .deadline(Duration::from_secs(10));
+
+        let mut attempts = 0;
+        let result: Result<i32, &str> = retry.execute(|| {
+            attempts += 1;
+            Err("oops")
+        });
+        // the 5th attempt would start after 12 seconds
+        assert_eq!((result, attempts), (Err("oops"), 4));
+        assert_eq!(clock.elapsed(), Duration::from_secs(9));
+    }
+
+}
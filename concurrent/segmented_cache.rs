@@ -0,0 +1,166 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex, MutexGuard};
+use data_structures::cache::Cache;
+use data_structures::cache_stats::CacheStats;
+
+/*
+   A bounded thread-safe cache split into N lock-striped segments.
+   Each key is mapped to a single segment, so threads working on different segments do not contend.
+   Every segment is a separate cache with its own eviction policy (e.g. LRU) and a 1/N share of the capacity,
+   all segments record into the same stats.
+ */
+pub struct SegmentedCache<K, V, C> {
+    segments: Arc<Vec<Mutex<C>>>,
+    stats: Arc<CacheStats>,
+    _entries: PhantomData<fn(K) -> V>
+}
+
+impl <K, V, C> Clone for SegmentedCache<K, V, C> {
+    fn clone(&self) -> Self {
+        SegmentedCache {
+            segments: Arc::clone(&self.segments),
+            stats: Arc::clone(&self.stats),
+            _entries: PhantomData
+        }
+    }
+}
+
+impl <K, V, C> SegmentedCache<K, V, C> where K: Hash + Eq, V: Clone, C: Cache<K, V> {
+    // segment_factory creates a segment from its capacity and the shared stats
+    pub fn new<F>(segments: usize, capacity: usize, segment_factory: F) -> Self
+        where F: Fn(usize, Arc<CacheStats>) -> C {
+        assert!(segments > 0 && capacity >= segments);
+
+        let stats = Arc::new(CacheStats::new());
+        let segments = (0..segments)
+            .map(|idx| {
+                // spread the remainder, so the total capacity is exact
+                let segment_capacity = capacity / segments + if idx < capacity % segments { 1 } else { 0 };
+                Mutex::new(segment_factory(segment_capacity, Arc::clone(&stats)))
+            }).collect();
+        SegmentedCache { segments: Arc::new(segments), stats, _entries: PhantomData }
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.segment(key).get(key)
+    }
+
+    pub fn put(&self, key: K, value: V) {
+        self.segment(&key).put(key, value);
+    }
+
+    pub fn remove(&self, key: &K) -> Option<V> {
+        self.segment(key).remove(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.segments.iter().map(|segment| segment.lock().unwrap().len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&self) {
+        self.segments.iter().for_each(|segment| segment.lock().unwrap().clear());
+    }
+
+    fn segment(&self, key: &K) -> MutexGuard<'_, C> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let idx = (hasher.finish() % self.segments.len() as u64) as usize;
+        self.segments[idx].lock().unwrap()
+    }
+}
+
+impl <K, V, C> Cache<K, V> for SegmentedCache<K, V, C> where K: Hash + Eq, V: Clone, C: Cache<K, V> {
+    fn get(&mut self, key: &K) -> Option<V> {
+        SegmentedCache::get(self, key)
+    }
+
+    fn put(&mut self, key: K, value: V) {
+        SegmentedCache::put(self, key, value);
+    }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        SegmentedCache::remove(self, key)
+    }
+
+    fn len(&self) -> usize {
+        SegmentedCache::len(self)
+    }
+
+    fn clear(&mut self) {
+        SegmentedCache::clear(self);
+    }
+
+    fn stats(&self) -> &CacheStats {
+        &self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::thread::JoinHandle;
+    use data_structures::cache_stats::EvictionCause;
+    use data_structures::lfu::LFUCache;
+    use data_structures::lru::LRUCache;
+    use super::*;
+
+    #[test]
+    fn test_bounded_capacity() {
+        let cache = SegmentedCache::new(4, 100, LRUCache::with_stats);
+        let tasks: Vec<JoinHandle<()>> = (0..8).map(|idx| (idx, cache.clone()))
+            .map(|(idx, cache)| {
+                thread::spawn(move || {
+                    for i in 0..100 {
+                        cache.put(idx * 100 + i, i);
+                    }
+                })
+            }).collect();
+
+        tasks.into_iter().for_each(|thread| {
+            thread.join();
+        });
+        assert!(cache.len() <= 100);
+        assert_eq!(cache.stats().snapshot().evictions(EvictionCause::Size), 800 - cache.len() as u64);
+    }
+
+    #[test]
+    fn test_reads_writes() {
+        let cache = SegmentedCache::new(8, 1000, LRUCache::with_stats);
+        let tasks: Vec<JoinHandle<()>> = (0..8).map(|idx| (idx, cache.clone()))
+            .map(|(idx, cache)| {
+                thread::spawn(move || {
+                    for i in 0..100 {
+                        cache.put(idx * 100 + i, i);
+                        assert_eq!(cache.get(&(idx * 100 + i)), Some(i));
+                    }
+                })
+            }).collect();
+
+        tasks.into_iter().for_each(|thread| {
+            thread.join().unwrap();
+        });
+        assert_eq!(cache.len(), 8 * 100);
+        assert_eq!(cache.stats().snapshot().hits, 8 * 100);
+    }
+
+    #[test]
+    fn test_eviction_policy() {
+        let mut cache = SegmentedCache::new(1, 2, LFUCache::with_stats);
+        cache.put(1, 1);
+        cache.get(&1);
+        cache.put(2, 2);
+        cache.put(3, 3);
+        assert_eq!(cache.get(&1), Some(1));
+        assert_eq!(cache.get(&2), None);
+        assert_eq!(cache.get_or_insert_with(2, |_| 20), 20);
+        cache.clear();
+        assert_eq!(cache.len(), 0);
+    }
+
+}
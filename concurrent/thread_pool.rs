@@ -26,6 +26,14 @@ impl<T> BlockingQueue<T> {
         self.sender.lock().unwrap().as_ref().unwrap().send(e);
     }
 
+    // false if the queue is stopped
+    pub fn try_push(&self, e: T) -> bool {
+        match self.sender.lock().unwrap().as_ref() {
+            Some(sender) => sender.send(e).is_ok(),
+            None => false
+        }
+    }
+
     pub fn pop(&self) -> Result<T, RecvError> {
         self.receiver.lock().unwrap().recv()
     }
@@ -33,6 +41,11 @@ impl<T> BlockingQueue<T> {
     pub fn stop(&self) {
         self.sender.lock().unwrap().take();
     }
+
+    // drops the elements left in the queue
+    pub fn clear(&self) {
+        while self.receiver.lock().unwrap().try_recv().is_ok() {}
+    }
 }
 
 impl<T> Clone for BlockingQueue<T> {
@@ -44,7 +57,7 @@ impl<T> Clone for BlockingQueue<T> {
     }
 }
 
-struct ThreadPool {
+pub struct ThreadPool {
     workers: Arc<Vec<JoinHandle<()>>>,
     task_queue: BlockingQueue<Task>,
     is_active: Arc<AtomicBool>
@@ -61,7 +74,7 @@ impl Clone for ThreadPool {
 }
 
 impl ThreadPool {
-    fn new(capacity: usize) -> Self {
+    pub fn new(capacity: usize) -> Self {
         let task_queue: BlockingQueue<Task> = BlockingQueue::new();
         let is_active = Arc::new(AtomicBool::new(true));
         let workers: Vec<JoinHandle<()>> = (0..capacity)
@@ -83,7 +96,7 @@ impl ThreadPool {
             is_active
         }
     }
-    fn execute<F>(&self, task: F)
+    pub fn execute<F>(&self, task: F)
         where F: FnOnce() + Send + 'static {
         if self.is_active.load(Ordering::Acquire) {
             self.task_queue.push(Box::new(task));
@@ -91,9 +104,16 @@ impl ThreadPool {
             panic!("Thread pool is shutdown")
         }
     }
-    fn shutdown(&self) {
-        if self.is_active.compare_exchange(true, false, Ordering::Release, Ordering::Relaxed).unwrap() {
+    // the task is dropped without running if the pool is shutdown
+    pub fn try_execute<F>(&self, task: F) -> bool
+        where F: FnOnce() + Send + 'static {
+        self.is_active.load(Ordering::Acquire) && self.task_queue.try_push(Box::new(task))
+    }
+    // the queued tasks are dropped, a repeated shutdown does nothing
+    pub fn shutdown(&self) {
+        if self.is_active.compare_exchange(true, false, Ordering::Release, Ordering::Relaxed).is_ok() {
             self.task_queue.stop();
+            self.task_queue.clear();
         }
     }
 
@@ -0,0 +1,109 @@
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+use crate::circuit_breaker::Error;
+
+// Cancelled when the call times out, a long running call can check it to stop early
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        CancellationToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Release);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Acquire)
+    }
+}
+
+/*
+   A time limiter bounds how long the caller waits for a blocking call.
+   The call runs on its own thread, if it does not complete within the timeout
+   the caller gets Error::Timeout and the token passed into the call is cancelled.
+   A call which ignores the token keeps running on its thread, its result is dropped.
+ */
+#[derive(Debug, Copy, Clone)]
+pub struct TimeLimiter {
+    timeout: Duration
+}
+
+impl TimeLimiter {
+    pub fn new(timeout: Duration) -> Self {
+        TimeLimiter {
+            timeout
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn execute<F, R, E>(&self, f: F) -> Result<R, Error<E>>
+        where F: FnOnce(CancellationToken) -> Result<R, E> + Send + 'static, R: Send + 'static, E: Send + 'static {
+        let token = CancellationToken::new();
+        let (sender, receiver) = channel();
+        let call_token = token.clone();
+        thread::Builder::new().name("time-limiter".to_string()).spawn(move || {
+            let _ = sender.send(panic::catch_unwind(AssertUnwindSafe(|| f(call_token))));
+        }).unwrap();
+
+        match receiver.recv_timeout(self.timeout) {
+            Ok(Ok(result)) => result.map_err(|e| Error::Custom(e)),
+            Ok(Err(payload)) => panic::resume_unwind(payload),
+            Err(RecvTimeoutError::Timeout) => {
+                token.cancel();
+                Err(Error::Timeout)
+            },
+            Err(RecvTimeoutError::Disconnected) => unreachable!("the call thread always sends the result")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+    use super::*;
+
+    #[test]
+    fn test_time_limiter() {
+        let time_limiter = TimeLimiter::new(Duration::from_millis(50));
+        assert_eq!(time_limiter.execute(|_| { if 1 > 0 { Ok(1) } else { Err("oops") } }), Ok(1));
+        assert_eq!(time_limiter.execute(|_| { if 1 > 0 { Err("oops") } else { Ok(1) } }), Err(Error::Custom("oops")));
+
+        let start = Instant::now();
+        let result = time_limiter.execute(|_| {
+            thread::sleep(Duration::from_secs(1));
+            if 1 > 0 { Ok(1) } else { Err("oops") }
+        });
+        assert_eq!(result, Err(Error::Timeout));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_cancellation() {
+        let time_limiter = TimeLimiter::new(Duration::from_millis(20));
+        let (sender, receiver) = channel();
+
+        let result = time_limiter.execute(move |token: CancellationToken| {
+            while !token.is_cancelled() {
+                thread::sleep(Duration::from_millis(1));
+            }
+            sender.send("cancelled").unwrap();
+            if 1 > 0 { Err("oops") } else { Ok(1) }
+        });
+        assert_eq!(result, Err(Error::Timeout));
+        assert_eq!(receiver.recv(), Ok("cancelled"));
+    }
+
+}
@@ -0,0 +1,62 @@
+use std::time::Instant;
+use crate::cache_stats::CacheStats;
+
+/*
+   A common interface of all cache implementations, so eviction policies can be swapped by configuration.
+   Values are returned by clone, as a concurrent cache cannot hand out references to its entries.
+ */
+pub trait Cache<K, V: Clone> {
+    fn get(&mut self, key: &K) -> Option<V>;
+
+    fn put(&mut self, key: K, value: V);
+
+    fn remove(&mut self, key: &K) -> Option<V>;
+
+    fn len(&self) -> usize;
+
+    fn clear(&mut self);
+
+    fn stats(&self) -> &CacheStats;
+
+    // returns the cached value or computes, caches and returns a new one
+    // not available on dyn Cache, so the trait stays usable as a trait object
+    fn get_or_insert_with<F>(&mut self, key: K, f: F) -> V where F: FnOnce(&K) -> V, Self: Sized {
+        if let Some(value) = self.get(&key) {
+            return value;
+        }
+        let start = Instant::now();
+        let value = f(&key);
+        self.stats().record_load(start.elapsed());
+        self.put(key, value.clone());
+        value
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lfu::LFUCache;
+    use crate::lru::LRUCache;
+    use crate::tiny_lfu::WTinyLFUCache;
+    use super::*;
+
+    #[test]
+    fn test_dyn_cache() {
+        for policy in ["lru", "lfu", "w-tinylfu"] {
+            let mut cache: Box<dyn Cache<u32, u32>> = match policy {
+                "lru" => Box::new(LRUCache::new(10)),
+                "lfu" => Box::new(LFUCache::new(10)),
+                _ => Box::new(WTinyLFUCache::new(10))
+            };
+            cache.put(1, 10);
+            assert_eq!(cache.get(&1), Some(10));
+            assert_eq!(cache.remove(&1), Some(10));
+            assert!(cache.is_empty());
+            assert_eq!(cache.stats().snapshot().hits, 1);
+        }
+    }
+
+}
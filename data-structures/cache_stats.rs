@@ -0,0 +1,161 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum EvictionCause {
+    // the entry was evicted to keep the cache within its capacity
+    Size,
+    // the entry value was overwritten by a put
+    Replaced,
+    // the entry was removed or the cache was cleared
+    Explicit
+}
+
+const EVICTION_CAUSES: usize = 3;
+
+/*
+   Statistics shared by all cache implementations: hits, misses, loads and evictions by cause.
+   Counters are atomic so a single instance can be recorded into from many threads.
+ */
+#[derive(Debug, Default)]
+pub struct CacheStats {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    loads: AtomicU64,
+    total_load_nanos: AtomicU64,
+    evictions: [AtomicU64; EVICTION_CAUSES]
+}
+
+// A point-in-time copy of CacheStats counters.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct CacheStatsSnapshot {
+    pub hits: u64,
+    pub misses: u64,
+    pub loads: u64,
+    pub total_load_time: Duration,
+    evictions: [u64; EVICTION_CAUSES]
+}
+
+impl CacheStats {
+    pub fn new() -> Self {
+        CacheStats::default()
+    }
+
+    pub fn record_hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_load(&self, load_time: Duration) {
+        self.loads.fetch_add(1, Ordering::Relaxed);
+        self.total_load_nanos.fetch_add(load_time.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_eviction(&self, cause: EvictionCause) {
+        self.record_evictions(cause, 1);
+    }
+
+    pub fn record_evictions(&self, cause: EvictionCause, count: u64) {
+        self.evictions[cause as usize].fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> CacheStatsSnapshot {
+        CacheStatsSnapshot {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            loads: self.loads.load(Ordering::Relaxed),
+            total_load_time: Duration::from_nanos(self.total_load_nanos.load(Ordering::Relaxed)),
+            evictions: self.evictions.each_ref().map(|counter| counter.load(Ordering::Relaxed))
+        }
+    }
+
+    pub fn reset(&self) {
+        self.hits.store(0, Ordering::Relaxed);
+        self.misses.store(0, Ordering::Relaxed);
+        self.loads.store(0, Ordering::Relaxed);
+        self.total_load_nanos.store(0, Ordering::Relaxed);
+        self.evictions.iter().for_each(|counter| counter.store(0, Ordering::Relaxed));
+    }
+}
+
+// the average of count durations adding up to total, zero if there are none
+pub fn average(total: Duration, count: u64) -> Duration {
+    if count == 0 {
+        Duration::ZERO
+    } else {
+        Duration::from_nanos((total.as_nanos() / count as u128) as u64)
+    }
+}
+
+impl CacheStatsSnapshot {
+    pub fn requests(&self) -> u64 {
+        self.hits + self.misses
+    }
+
+    pub fn hit_ratio(&self) -> f64 {
+        if self.requests() == 0 {
+            1.0
+        } else {
+            self.hits as f64 / self.requests() as f64
+        }
+    }
+
+    pub fn average_load_time(&self) -> Duration {
+        average(self.total_load_time, self.loads)
+    }
+
+    pub fn evictions(&self, cause: EvictionCause) -> u64 {
+        self.evictions[cause as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot() {
+        let stats = CacheStats::new();
+        stats.record_hit();
+        stats.record_hit();
+        stats.record_hit();
+        stats.record_miss();
+        stats.record_load(Duration::from_millis(10));
+        stats.record_load(Duration::from_millis(30));
+        stats.record_eviction(EvictionCause::Size);
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.requests(), 4);
+        assert_eq!(snapshot.hit_ratio(), 0.75);
+        assert_eq!(snapshot.loads, 2);
+        assert_eq!(snapshot.average_load_time(), Duration::from_millis(20));
+        assert_eq!(snapshot.evictions(EvictionCause::Size), 1);
+        assert_eq!(snapshot.evictions(EvictionCause::Replaced), 0);
+    }
+
+    #[test]
+    fn test_average() {
+        assert_eq!(average(Duration::from_secs(1), 0), Duration::ZERO);
+        // the count does not fit in a u32
+        assert_eq!(average(Duration::from_secs(1 << 32), 1 << 32), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_reset() {
+        let stats = CacheStats::new();
+        stats.record_hit();
+        stats.record_load(Duration::from_millis(10));
+        stats.record_eviction(EvictionCause::Replaced);
+        let snapshot = stats.snapshot();
+
+        stats.reset();
+
+        // snapshots are not affected by later changes
+        assert_eq!(snapshot.hits, 1);
+        assert_eq!(stats.snapshot(), CacheStatsSnapshot::default());
+    }
+
+}
@@ -1,12 +1,15 @@
 use std::hash::Hash;
-use std::fmt::Debug;
+use std::sync::Arc;
 use std::collections::HashMap;
 use std::cmp::Ordering;
+use crate::cache::Cache;
+use crate::cache_stats::{CacheStats, EvictionCause};
 
-struct LFUCache<K, V> {
+pub struct LFUCache<K, V> {
     data: Vec<Entry<K, V>>,
     capacity: usize,
-    indexes: HashMap<K, usize>
+    indexes: HashMap<K, usize>,
+    stats: Arc<CacheStats>
 }
 
 #[derive(Debug)]
@@ -46,31 +49,38 @@ impl <K, V> Ord for Entry<K, V> {
 }
 
 // A LFU cache backed by Binary Heap.
-impl <K, V> LFUCache<K, V> where K: Ord + Debug + Hash + Copy, V: Copy + Debug + PartialEq {
-    fn new(capacity: usize) -> Self {
-        Self { data: Vec::new(), capacity, indexes: HashMap::new() }
+impl <K, V> LFUCache<K, V> where K: Hash + Eq + Clone, V: Clone {
+    pub fn new(capacity: usize) -> Self {
+        Self::with_stats(capacity, Arc::new(CacheStats::new()))
+    }
+
+    pub fn with_stats(capacity: usize, stats: Arc<CacheStats>) -> Self {
+        Self { data: Vec::new(), capacity, indexes: HashMap::new(), stats }
     }
 
     fn put(&mut self, key: K, value: V) {
         if let Some(&idx) = self.indexes.get(&key) {
-            let entry= &self.data[idx];
-            if entry.value != value {
-                self.del(idx);
-                self.put_entry(Entry::new(key, value));
-            }
+            // the entry keeps its frequency
+            self.data[idx].value = value;
+            self.stats.record_eviction(EvictionCause::Replaced);
         } else {
             self.put_entry(Entry::new(key, value));
         }
     }
 
     fn get(&mut self, key: K) -> Option<V> {
-        if let Some(&idx) = self.indexes.get(&key) {
+        self.get_ref(&key).cloned()
+    }
+
+    fn get_ref(&mut self, key: &K) -> Option<&V> {
+        if let Some(&idx) = self.indexes.get(key) {
             let mut entry = self.del(idx).unwrap();
-            let value = entry.value.clone();
             entry.increment();
             self.put_entry(entry);
-            return Some(value);
+            self.stats.record_hit();
+            return self.indexes.get(key).map(|&idx| &self.data[idx].value);
         }
+        self.stats.record_miss();
         None
     }
 
@@ -78,6 +88,7 @@ impl <K, V> LFUCache<K, V> where K: Ord + Debug + Hash + Copy, V: Copy + Debug +
         if self.data.len() == self.capacity {
             // remove the least frequent entry
             self.del(0).unwrap();
+            self.stats.record_eviction(EvictionCause::Size);
         }
         self.indexes.insert(entry.key.clone(), self.data.len());
         self.data.push(entry);
@@ -90,10 +101,12 @@ impl <K, V> LFUCache<K, V> where K: Ord + Debug + Hash + Copy, V: Copy + Debug +
             self.data.swap(idx, len - 1);
             let entry = self.data.pop().unwrap();
             self.indexes.remove(&entry.key);
-            if self.data.len() > 0 {
-                self.indexes.insert(self.data[0].key, 0);
+            if idx < self.data.len() {
+                // the last entry was moved to idx, restore the heap order in both directions
+                self.indexes.insert(self.data[idx].key.clone(), idx);
+                self.heapify_top_down(idx);
+                self.heapify_bottom_up(idx);
             }
-            self.heapify_top_down(idx);
             Some(entry)
         } else {
             None
@@ -136,6 +149,36 @@ impl <K, V> LFUCache<K, V> where K: Ord + Debug + Hash + Copy, V: Copy + Debug +
     }
 }
 
+impl <K, V> Cache<K, V> for LFUCache<K, V> where K: Hash + Eq + Clone, V: Clone {
+    fn get(&mut self, key: &K) -> Option<V> {
+        self.get_ref(key).cloned()
+    }
+
+    fn put(&mut self, key: K, value: V) {
+        LFUCache::put(self, key, value);
+    }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        let &idx = self.indexes.get(key)?;
+        self.stats.record_eviction(EvictionCause::Explicit);
+        self.del(idx).map(|entry| entry.value)
+    }
+
+    fn len(&self) -> usize {
+        LFUCache::len(self)
+    }
+
+    fn clear(&mut self) {
+        self.stats.record_evictions(EvictionCause::Explicit, self.data.len() as u64);
+        self.data.clear();
+        self.indexes.clear();
+    }
+
+    fn stats(&self) -> &CacheStats {
+        &self.stats
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -172,4 +215,38 @@ mod tests {
         assert_eq!(cache.get(3), Some(3));
     }
 
+    #[test]
+    fn test_lfu_stats() {
+        let mut cache = LFUCache::new(2);
+        cache.put(1, 1);
+        cache.put(1, 2);
+        cache.put(2, 2);
+        cache.put(3, 3);
+        cache.get(1);
+        cache.get(3);
+        let stats = cache.stats().snapshot();
+        assert_eq!(stats.hits, 1);
+        assert_eq!(stats.misses, 1);
+        assert_eq!(stats.evictions(EvictionCause::Replaced), 1);
+        assert_eq!(stats.evictions(EvictionCause::Size), 1);
+    }
+
+    #[test]
+    fn test_lfu_cache_trait() {
+        let mut cache = LFUCache::new(2);
+        assert_eq!(cache.get_or_insert_with(String::from("a"), |key| key.len()), 1);
+        assert_eq!(cache.get_or_insert_with(String::from("a"), |_| 0), 1);
+        Cache::put(&mut cache, String::from("bb"), 2);
+        // `a` is more frequently used, so `bb` is evicted
+        Cache::put(&mut cache, String::from("ccc"), 3);
+        assert_eq!(Cache::get(&mut cache, &String::from("bb")), None);
+        assert_eq!(cache.remove(&String::from("a")), Some(1));
+        assert_eq!(Cache::get(&mut cache, &String::from("ccc")), Some(3));
+        cache.clear();
+        assert!(cache.is_empty());
+        let stats = cache.stats().snapshot();
+        assert_eq!(stats.loads, 1);
+        assert_eq!(stats.evictions(EvictionCause::Explicit), 2);
+    }
+
 }
@@ -1,34 +1,88 @@
 use std::collections::HashMap;
 use std::hash::Hash;
+use std::sync::Arc;
 use linked_hash_map::LinkedHashMap;
+use crate::cache::Cache;
+use crate::cache_stats::{CacheStats, EvictionCause};
 
-struct LRUCache<K, V> {
+pub struct LRUCache<K, V> {
     map: LinkedHashMap<K, V>,
-    capacity: usize
+    capacity: usize,
+    stats: Arc<CacheStats>
 }
 
 impl<K, V> LRUCache<K, V>
     where K: Hash + Eq {
 
-    fn new(capacity: usize) -> Self {
-        LRUCache { map: LinkedHashMap::new(), capacity }
+    pub fn new(capacity: usize) -> Self {
+        Self::with_stats(capacity, Arc::new(CacheStats::new()))
+    }
+
+    // the stats can be shared with other caches, e.g. segments of one concurrent cache
+    pub fn with_stats(capacity: usize, stats: Arc<CacheStats>) -> Self {
+        LRUCache { map: LinkedHashMap::new(), capacity, stats }
     }
 
     fn put(&mut self, key: K, value: V) {
-        self.map.insert(key, value);
+        if self.map.insert(key, value).is_some() {
+            self.stats.record_eviction(EvictionCause::Replaced);
+        }
         if self.map.len() > self.capacity {
             // evict
             self.map.pop_front();
+            self.stats.record_eviction(EvictionCause::Size);
         }
     }
 
     fn get(&mut self, key: K) -> Option<&V> {
-        self.map.get_refresh(&key)
-            .map(|x| &*x)
+        self.get_ref(&key)
+    }
+
+    fn get_ref(&mut self, key: &K) -> Option<&V> {
+        let value = self.map.get_refresh(key);
+        if value.is_some() {
+            self.stats.record_hit();
+        } else {
+            self.stats.record_miss();
+        }
+        value.map(|x| &*x)
     }
 
 }
 
+impl<K, V> Cache<K, V> for LRUCache<K, V>
+    where K: Hash + Eq, V: Clone {
+
+    fn get(&mut self, key: &K) -> Option<V> {
+        self.get_ref(key).cloned()
+    }
+
+    fn put(&mut self, key: K, value: V) {
+        LRUCache::put(self, key, value);
+    }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        let value = self.map.remove(key);
+        if value.is_some() {
+            self.stats.record_eviction(EvictionCause::Explicit);
+        }
+        value
+    }
+
+    fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    fn clear(&mut self) {
+        self.stats.record_evictions(EvictionCause::Explicit, self.map.len() as u64);
+        self.map.clear();
+    }
+
+    fn stats(&self) -> &CacheStats {
+        &self.stats
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -65,4 +119,36 @@ mod tests {
         assert_eq!(cache.get(3), Some(&3));
     }
 
+    #[test]
+    fn test_lru_stats() {
+        let mut cache = LRUCache::new(2);
+        cache.put(1, 1);
+        cache.put(1, 2);
+        cache.put(2, 2);
+        cache.put(3, 3);
+        cache.get(1);
+        cache.get(2);
+        let stats = cache.stats().snapshot();
+        assert_eq!(stats.hits, 1);
+        assert_eq!(stats.misses, 1);
+        assert_eq!(stats.evictions(EvictionCause::Replaced), 1);
+        assert_eq!(stats.evictions(EvictionCause::Size), 1);
+    }
+
+    #[test]
+    fn test_lru_cache_trait() {
+        let mut cache = LRUCache::new(2);
+        assert_eq!(cache.get_or_insert_with(1, |&key| key * 10), 10);
+        assert_eq!(cache.get_or_insert_with(1, |_| 0), 10);
+        Cache::put(&mut cache, 2, 20);
+        assert_eq!(Cache::get(&mut cache, &2), Some(20));
+        assert_eq!(cache.remove(&1), Some(10));
+        assert_eq!(cache.remove(&1), None);
+        cache.clear();
+        assert!(cache.is_empty());
+        let stats = cache.stats().snapshot();
+        assert_eq!(stats.loads, 1);
+        assert_eq!(stats.evictions(EvictionCause::Explicit), 2);
+    }
+
 }
@@ -0,0 +1,307 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use linked_hash_map::LinkedHashMap;
+use crate::cache::Cache;
+use crate::cache_stats::{CacheStats, EvictionCause};
+
+// seeds used to derive an independent index for each row of the sketch
+static SEEDS: [u64; 4] = [0xc3a5c85c97cb3127, 0xb492b66fbe98f273, 0x9ae16a3b2f90404f, 0xcbf29ce484222325];
+// counters saturate at 15 (4 bits), like in the original TinyLFU paper
+static MAX_FREQUENCY: u8 = 15;
+
+/*
+   A count-min sketch estimating the access frequency of keys.
+   Each key is mapped to one counter in every row, the estimate is the minimum of these counters.
+   Once sample_size increments are recorded, all counters are halved so old popularity fades out.
+ */
+struct CountMinSketch {
+    table: Vec<u8>,
+    width: usize,
+    additions: usize,
+    sample_size: usize
+}
+
+impl CountMinSketch {
+    fn new(capacity: usize) -> Self {
+        let width = (4 * capacity.max(1)).next_power_of_two();
+        CountMinSketch {
+            table: vec![0; width * SEEDS.len()],
+            width,
+            additions: 0,
+            sample_size: 10 * capacity.max(1)
+        }
+    }
+
+    fn index(&self, hash: u64, row: usize) -> usize {
+        let h = (hash ^ SEEDS[row]).wrapping_mul(0x9e3779b97f4a7c15);
+        row * self.width + ((h >> 32) as usize & (self.width - 1))
+    }
+
+    fn increment(&mut self, hash: u64) {
+        let mut incremented = false;
+        for row in 0..SEEDS.len() {
+            let idx = self.index(hash, row);
+            if self.table[idx] < MAX_FREQUENCY {
+                self.table[idx] += 1;
+                incremented = true;
+            }
+        }
+        if incremented {
+            self.additions += 1;
+            if self.additions >= self.sample_size {
+                self.reset();
+            }
+        }
+    }
+
+    fn frequency(&self, hash: u64) -> u8 {
+        (0..SEEDS.len())
+            .map(|row| self.table[self.index(hash, row)])
+            .min()
+            .unwrap()
+    }
+
+    fn reset(&mut self) {
+        self.table.iter_mut().for_each(|counter| *counter >>= 1);
+        self.additions /= 2;
+    }
+}
+
+/*
+   A W-TinyLFU cache resistant to scan pollution.
+   New entries land in a small LRU window (1% of the capacity).
+   Entries evicted from the window compete for a place in the main region with the main region's victim,
+   the one estimated by the frequency sketch as more popular wins.
+   The main region is a segmented LRU: entries start in the probation segment
+   and are promoted to the protected segment (80% of the main region) when accessed again.
+ */
+pub struct WTinyLFUCache<K, V> {
+    window: LinkedHashMap<K, V>,
+    probation: LinkedHashMap<K, V>,
+    protected: LinkedHashMap<K, V>,
+    sketch: CountMinSketch,
+    stats: Arc<CacheStats>,
+    window_capacity: usize,
+    main_capacity: usize,
+    protected_capacity: usize
+}
+
+impl<K, V> WTinyLFUCache<K, V>
+    where K: Hash + Eq {
+
+    pub fn new(capacity: usize) -> Self {
+        Self::with_stats(capacity, Arc::new(CacheStats::new()))
+    }
+
+    pub fn with_stats(capacity: usize, stats: Arc<CacheStats>) -> Self {
+        assert!(capacity > 0);
+
+        let window_capacity = (capacity / 100).max(1);
+        let main_capacity = capacity - window_capacity;
+        WTinyLFUCache {
+            window: LinkedHashMap::new(),
+            probation: LinkedHashMap::new(),
+            protected: LinkedHashMap::new(),
+            sketch: CountMinSketch::new(capacity),
+            stats,
+            window_capacity,
+            main_capacity,
+            protected_capacity: main_capacity * 8 / 10
+        }
+    }
+
+    fn put(&mut self, key: K, value: V) {
+        self.sketch.increment(Self::hash(&key));
+
+        if let Some(entry) = self.window.get_refresh(&key) {
+            *entry = value;
+            self.stats.record_eviction(EvictionCause::Replaced);
+        } else if let Some(entry) = self.protected.get_refresh(&key) {
+            *entry = value;
+            self.stats.record_eviction(EvictionCause::Replaced);
+        } else if self.probation.contains_key(&key) {
+            self.probation.remove(&key);
+            self.stats.record_eviction(EvictionCause::Replaced);
+            self.promote(key, value);
+        } else {
+            self.window.insert(key, value);
+            if self.window.len() > self.window_capacity {
+                let (candidate_key, candidate_value) = self.window.pop_front().unwrap();
+                self.admit(candidate_key, candidate_value);
+            }
+        }
+    }
+
+    fn get(&mut self, key: K) -> Option<&V> {
+        self.sketch.increment(Self::hash(&key));
+
+        if self.window.contains_key(&key) {
+            self.stats.record_hit();
+            return self.window.get_refresh(&key).map(|x| &*x);
+        }
+        if self.protected.contains_key(&key) {
+            self.stats.record_hit();
+            return self.protected.get_refresh(&key).map(|x| &*x);
+        }
+        if let Some(value) = self.probation.remove(&key) {
+            self.stats.record_hit();
+            return self.promote(key, value);
+        }
+        self.stats.record_miss();
+        None
+    }
+
+    fn len(&self) -> usize {
+        self.window.len() + self.probation.len() + self.protected.len()
+    }
+
+    // move an entry to the protected segment, demote the least recently used protected entry if it is full
+    fn promote(&mut self, key: K, value: V) -> Option<&V> {
+        if self.protected.len() >= self.protected_capacity {
+            if let Some((demoted_key, demoted_value)) = self.protected.pop_front() {
+                self.probation.insert(demoted_key, demoted_value);
+            }
+        }
+        if self.protected_capacity == 0 {
+            self.probation.insert(key, value);
+            return self.probation.back().map(|(_, value)| value);
+        }
+        self.protected.insert(key, value);
+        self.protected.back().map(|(_, value)| value)
+    }
+
+    // an entry evicted from the window enters the main region only if it is more popular than the main region's victim
+    fn admit(&mut self, key: K, value: V) {
+        if self.probation.len() + self.protected.len() < self.main_capacity {
+            self.probation.insert(key, value);
+            return;
+        }
+        let victim_frequency = self.probation.front()
+            .or_else(|| self.protected.front())
+            .map(|(victim_key, _)| self.sketch.frequency(Self::hash(victim_key)));
+        if let Some(victim_frequency) = victim_frequency {
+            if self.sketch.frequency(Self::hash(&key)) > victim_frequency {
+                if self.probation.pop_front().is_none() {
+                    self.protected.pop_front();
+                }
+                self.probation.insert(key, value);
+            }
+        }
+        // either the victim or the candidate is evicted
+        self.stats.record_eviction(EvictionCause::Size);
+    }
+
+    fn hash(key: &K) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+impl<K, V> Cache<K, V> for WTinyLFUCache<K, V>
+    where K: Hash + Eq + Clone, V: Clone {
+
+    fn get(&mut self, key: &K) -> Option<V> {
+        WTinyLFUCache::get(self, key.clone()).cloned()
+    }
+
+    fn put(&mut self, key: K, value: V) {
+        WTinyLFUCache::put(self, key, value);
+    }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        let value = self.window.remove(key)
+            .or_else(|| self.probation.remove(key))
+            .or_else(|| self.protected.remove(key));
+        if value.is_some() {
+            self.stats.record_eviction(EvictionCause::Explicit);
+        }
+        value
+    }
+
+    fn len(&self) -> usize {
+        WTinyLFUCache::len(self)
+    }
+
+    // the frequency sketch is kept, so popular keys are still favoured after clearing
+    fn clear(&mut self) {
+        self.stats.record_evictions(EvictionCause::Explicit, WTinyLFUCache::len(self) as u64);
+        self.window.clear();
+        self.probation.clear();
+        self.protected.clear();
+    }
+
+    fn stats(&self) -> &CacheStats {
+        &self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tiny_lfu_value_update() {
+        let mut cache = WTinyLFUCache::new(2);
+        cache.put(1, 1);
+        cache.put(1, 2);
+        assert_eq!(cache.get(1), Some(&2));
+    }
+
+    #[test]
+    fn test_tiny_lfu_capacity() {
+        let mut cache = WTinyLFUCache::new(100);
+        for i in 0..1000 {
+            cache.put(i, i);
+            assert!(cache.len() <= 100);
+        }
+        assert_eq!(cache.len(), 100);
+        assert_eq!(cache.stats().snapshot().evictions(EvictionCause::Size), 900);
+    }
+
+    #[test]
+    fn test_tiny_lfu_scan_resistance() {
+        let mut cache = WTinyLFUCache::new(100);
+        // build up the working set
+        for _ in 0..5 {
+            for i in 0..50 {
+                cache.put(i, i);
+                cache.get(i);
+            }
+        }
+        // a one-off bulk read of many keys
+        for i in 1000..2000 {
+            cache.put(i, i);
+        }
+        // the working set survives the scan
+        for i in 0..50 {
+            assert_eq!(cache.get(i), Some(&i));
+        }
+    }
+
+    #[test]
+    fn test_tiny_lfu_cache_trait() {
+        let mut cache = WTinyLFUCache::new(10);
+        assert_eq!(cache.get_or_insert_with(1, |&key| key * 10), 10);
+        assert_eq!(cache.get_or_insert_with(1, |_| 0), 10);
+        Cache::put(&mut cache, 2, 20);
+        assert_eq!(cache.remove(&1), Some(10));
+        assert_eq!(cache.remove(&1), None);
+        assert_eq!(Cache::len(&cache), 1);
+        cache.clear();
+        assert!(cache.is_empty());
+        assert_eq!(cache.stats().snapshot().evictions(EvictionCause::Explicit), 2);
+    }
+
+    #[test]
+    fn test_count_min_sketch_reset() {
+        let mut sketch = CountMinSketch::new(1);
+        (0..9).for_each(|_| sketch.increment(42));
+        assert_eq!(sketch.frequency(42), 9);
+        // the 10th increment reaches the sample size and halves all counters
+        sketch.increment(42);
+        assert_eq!(sketch.frequency(42), 5);
+    }
+
+}